@@ -5,6 +5,8 @@ use thiserror::Error as ThisError;
 pub enum Error {
     #[error("SystemError: {cause}")]
     SystemError { cause: anyhow::Error },
+    #[error("CodecError: {cause}")]
+    CodecError { cause: anyhow::Error },
 }
 
 impl From<dragonfly::Error> for Error {
@@ -0,0 +1,130 @@
+//! Names of the dragonfly keys and channels used by the domain layer.
+//!
+//! Every name starts with a namespace so that several deployments can share a database, and the
+//! keys of a room are `<namespace>:room:<room name>:<kind>` so that no room name can collide with
+//! another key.
+
+pub const DEFAULT_NAMESPACE: &str = "chat";
+
+/// Names of the keys and channels of a namespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keys {
+    namespace: String,
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Self::new(DEFAULT_NAMESPACE)
+    }
+}
+
+impl Keys {
+    pub fn new<S: Into<String>>(namespace: S) -> Self {
+        Self {
+            namespace: namespace.into(),
+        }
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    fn room_key(&self, room_name: &str, kind: &str) -> String {
+        format!("{}:room:{}:{}", self.namespace, room_name, kind)
+    }
+
+    /// PUB/SUB channel carrying the messages of all rooms.
+    ///
+    /// The default namespace keeps the bare channel name servers used before namespaces, so that
+    /// they keep talking to each other during a rolling upgrade.
+    pub fn channel(&self, channel_name: &str) -> String {
+        if self.namespace == DEFAULT_NAMESPACE {
+            channel_name.to_owned()
+        } else {
+            format!("{}:channel:{}", self.namespace, channel_name)
+        }
+    }
+
+    /// Set of the names of all existing rooms.
+    pub fn room_directory(&self) -> String {
+        format!("{}:rooms", self.namespace)
+    }
+
+    /// Set of the usernames in the room.
+    pub fn room_members(&self, room_name: &str) -> String {
+        self.room_key(room_name, "members")
+    }
+
+    /// Hash of the number of sessions of each user in the room.
+    pub fn room_sessions(&self, room_name: &str) -> String {
+        self.room_key(room_name, "sessions")
+    }
+
+    /// Hash of the digest of the identity token of each user in the room.
+    pub fn room_identities(&self, room_name: &str) -> String {
+        self.room_key(room_name, "identities")
+    }
+
+    pub fn room_metadata(&self, room_name: &str) -> String {
+        self.room_key(room_name, "metadata")
+    }
+
+    /// Hash of the digest of the invite token of each invited user.
+    pub fn room_invites(&self, room_name: &str) -> String {
+        self.room_key(room_name, "invite-tokens")
+    }
+
+    /// Hash of the id of the last message each user read in the room.
+    pub fn room_receipts(&self, room_name: &str) -> String {
+        self.room_key(room_name, "receipts")
+    }
+
+    /// Set of the rooms in which the user has a read receipt.
+    pub fn user_read_rooms(&self, username: &str) -> String {
+        format!("{}:user:{}:read-rooms", self.namespace, username)
+    }
+
+    pub fn room_history(&self, room_name: &str) -> String {
+        self.room_key(room_name, "history")
+    }
+
+    /// Sorted set of `<term>:<message id>` entries for the search terms of the messages in the
+    /// history of the room, all with the same score so that they are ranged by term.
+    pub fn room_search_index(&self, room_name: &str) -> String {
+        self.room_key(room_name, "search-index")
+    }
+
+    /// Hash of the search terms of each message in the history of the room, separated by spaces.
+    pub fn room_search_terms(&self, room_name: &str) -> String {
+        self.room_key(room_name, "search-message-terms")
+    }
+
+    /// Counter of the message ids of the room.
+    pub fn room_sequence(&self, room_name: &str) -> String {
+        self.room_key(room_name, "sequence")
+    }
+
+    pub fn room_webhooks(&self, room_name: &str) -> String {
+        self.room_key(room_name, "webhooks")
+    }
+
+    pub fn room_dead_letters(&self, room_name: &str) -> String {
+        self.room_key(room_name, "webhook-dead-letters")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_namespace() {
+        let keys = Keys::default();
+        assert_eq!(keys.room_members("sessions"), "chat:room:sessions:members");
+        assert_eq!(keys.channel("test"), "test");
+        let keys = Keys::new("staging");
+        assert_eq!(keys.channel("test"), "staging:channel:test");
+        assert_eq!(keys.room_directory(), "staging:rooms");
+        assert_eq!(keys.room_history("a:b"), "staging:room:a:b:history");
+    }
+}
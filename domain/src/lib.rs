@@ -1,5 +1,7 @@
+pub mod keys;
 pub mod models;
 pub mod services;
+pub mod storage;
 
 mod error;
 
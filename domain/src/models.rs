@@ -1,5 +1,15 @@
 mod chat_message;
+mod message_codec;
+mod room_message;
+mod room_metadata;
+mod search_hit;
 mod server_id;
+mod webhook;
 
 pub use chat_message::*;
+pub use message_codec::*;
+pub use room_message::*;
+pub use room_metadata::*;
+pub use search_hit::*;
 pub use server_id::*;
+pub use webhook::*;
@@ -1,9 +1,10 @@
+use super::message_codec::decode_payload;
+use super::room_message::RoomMessage;
 use super::server_id::ServerId;
-use dragonfly::{FromRedisValue, RedisErrorKind, RedisResult, RedisValue, RedisWrite, ToRedisArgs};
+use dragonfly::{FromRedisValue, RedisErrorKind, RedisResult, RedisValue};
 use serde::{Deserialize, Serialize};
-use std::str::from_utf8;
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ChatMessage {
     Join {
         username: String,
@@ -18,9 +19,57 @@ pub enum ChatMessage {
         room_name: String,
         context: String,
     },
+    /// What the user does, e.g. `/me waves`.
+    Action {
+        username: String,
+        room_name: String,
+        action: String,
+    },
+    Rename {
+        username: String,
+        room_name: String,
+        new_username: String,
+    },
+    /// Sets the topic of the room, or clears it with `None`.
+    SetTopic {
+        username: String,
+        room_name: String,
+        topic: Option<String>,
+    },
+    /// Grants `invitee` access to the room.
+    Invite {
+        username: String,
+        room_name: String,
+        invitee: String,
+    },
+    /// The user read the messages of the room up to `message_id` (a `RoomMessage::id`).
+    ReadUpTo {
+        username: String,
+        room_name: String,
+        message_id: u64,
+    },
 }
 
 impl ChatMessage {
+    pub fn room_name(&self) -> &str {
+        match self {
+            Self::Join { room_name, .. }
+            | Self::Leave { room_name, .. }
+            | Self::Chat { room_name, .. }
+            | Self::Action { room_name, .. }
+            | Self::Rename { room_name, .. }
+            | Self::SetTopic { room_name, .. }
+            | Self::Invite { room_name, .. }
+            | Self::ReadUpTo { room_name, .. } => room_name,
+        }
+    }
+
+    /// Whether the message is appended to the history of its room; the other ones are only
+    /// broadcast.
+    pub fn is_stored(&self) -> bool {
+        !matches!(self, Self::ReadUpTo { .. })
+    }
+
     pub fn message_context(&self) -> String {
         match self {
             Self::Join {
@@ -36,45 +85,200 @@ impl ChatMessage {
                 room_name: _,
                 context,
             } => format!("{}: {}", username, context),
+            Self::Action {
+                username,
+                room_name: _,
+                action,
+            } => format!("* {} {}", username, action),
+            Self::Rename {
+                username,
+                room_name: _,
+                new_username,
+            } => format!("{} is now {}.", username, new_username),
+            Self::SetTopic {
+                username,
+                room_name: _,
+                topic: Some(topic),
+            } => format!("{} set the topic: {}", username, topic),
+            Self::SetTopic {
+                username,
+                room_name: _,
+                topic: None,
+            } => format!("{} cleared the topic.", username),
+            Self::Invite {
+                username,
+                room_name: _,
+                invitee,
+            } => format!("{} invited {}.", username, invitee),
+            Self::ReadUpTo {
+                username,
+                room_name: _,
+                message_id,
+            } => format!("{} read up to #{}.", username, message_id),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// Version of the `ChatMessage` schema written by this build.
+///
+/// Bump it whenever a variant or a field is added to `ChatMessage`.
+pub const CHAT_MESSAGE_SCHEMA_VERSION: u32 = 7;
+
+/// Envelopes written before schema versioning was introduced carry no version.
+fn legacy_schema_version() -> u32 {
+    1
+}
+
+/// A `ChatMessage` read from the channel.
+///
+/// Messages written by a newer server may use variants this build doesn't know about; they are
+/// kept as `Unknown` instead of failing the decoding of the whole envelope.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ChatMessagePayload {
+    Known(ChatMessage),
+    Unknown(serde_json::Value),
+}
+
+impl From<ChatMessage> for ChatMessagePayload {
+    fn from(value: ChatMessage) -> Self {
+        Self::Known(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct IdLabeledMessage {
+    #[serde(default = "legacy_schema_version")]
+    pub version: u32,
     pub id: ServerId,
-    pub msg: ChatMessage,
+    /// `RoomMessage::id` of the message, `0` for envelopes written before history was kept.
+    #[serde(default)]
+    pub message_id: u64,
+    #[serde(default)]
+    pub timestamp: i64,
+    pub msg: ChatMessagePayload,
+}
+
+impl IdLabeledMessage {
+    pub fn new(id: ServerId, message: RoomMessage) -> Self {
+        Self {
+            version: CHAT_MESSAGE_SCHEMA_VERSION,
+            id,
+            message_id: message.id,
+            timestamp: message.timestamp,
+            msg: message.msg.into(),
+        }
+    }
 }
 
 impl FromRedisValue for IdLabeledMessage {
     fn from_redis_value(v: &RedisValue) -> RedisResult<Self> {
         match *v {
-            RedisValue::Data(ref bytes) => {
-                let json_string = from_utf8(bytes)?;
-                match serde_json::from_str::<IdLabeledMessage>(json_string) {
-                    Ok(result) => Ok(result),
-                    Err(_) => Err((
-                        RedisErrorKind::TypeError,
-                        "illegal json value for IdLabeledMessage",
-                    )
-                        .into()),
-                }
-            }
+            RedisValue::Data(ref bytes) => match decode_payload(bytes) {
+                Ok(result) => Ok(result),
+                Err(_) => Err((
+                    RedisErrorKind::TypeError,
+                    "illegal payload for IdLabeledMessage",
+                )
+                    .into()),
+            },
             _ => Err((
                 RedisErrorKind::TypeError,
-                "Response type not json string compatible.",
+                "Response type not payload compatible.",
             )
                 .into()),
         }
     }
 }
 
-impl ToRedisArgs for IdLabeledMessage {
-    fn write_redis_args<W>(&self, out: &mut W)
-    where
-        W: ?Sized + RedisWrite,
-    {
-        let json_string = serde_json::to_string(self).unwrap();
-        out.write_arg(json_string.as_bytes())
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{encode_payload, PayloadFormat};
+
+    fn chat() -> ChatMessage {
+        ChatMessage::Chat {
+            username: "alice".to_owned(),
+            room_name: "test-room".to_owned(),
+            context: "hello".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_decode_legacy_envelope() {
+        let legacy =
+            br#"{"id":"server-1","msg":{"Join":{"username":"alice","room_name":"test-room"}}}"#;
+        let message = decode_payload(legacy).unwrap();
+        assert_eq!(message.version, 1);
+        assert_eq!(message.id, ServerId::from("server-1".to_owned()));
+        assert_eq!(message.message_id, 0);
+        assert_eq!(
+            message.msg,
+            ChatMessagePayload::Known(ChatMessage::Join {
+                username: "alice".to_owned(),
+                room_name: "test-room".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_round_trip_current_version() {
+        let message = IdLabeledMessage::new(ServerId::new(), RoomMessage::new(42, chat()));
+        for format in [
+            PayloadFormat::Json,
+            PayloadFormat::MessagePack,
+            PayloadFormat::Cbor,
+        ] {
+            let payload = encode_payload(format.codec(), &message).unwrap();
+            let decoded = decode_payload(&payload).unwrap();
+            assert_eq!(decoded.version, CHAT_MESSAGE_SCHEMA_VERSION);
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn test_decode_unknown_variant_from_newer_version() {
+        let newer = serde_json::json!({
+            "version": CHAT_MESSAGE_SCHEMA_VERSION + 1,
+            "id": "server-2",
+            "msg": {"Reaction": {"username": "bob", "room_name": "test-room", "emoji": "+1"}},
+        });
+        for format in [
+            PayloadFormat::Json,
+            PayloadFormat::MessagePack,
+            PayloadFormat::Cbor,
+        ] {
+            let body = match format {
+                PayloadFormat::Json => serde_json::to_vec(&newer).unwrap(),
+                PayloadFormat::MessagePack => rmp_serde::to_vec_named(&newer).unwrap(),
+                PayloadFormat::Cbor => {
+                    let mut bytes = Vec::new();
+                    ciborium::ser::into_writer(&newer, &mut bytes).unwrap();
+                    bytes
+                }
+            };
+            let mut payload = vec![0, 1, format.id()];
+            payload.extend_from_slice(&body);
+            let decoded = decode_payload(&payload).unwrap();
+            assert_eq!(decoded.version, CHAT_MESSAGE_SCHEMA_VERSION + 1);
+            assert_eq!(
+                decoded.msg,
+                ChatMessagePayload::Unknown(newer["msg"].clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_known_variant_from_newer_version() {
+        let newer = serde_json::json!({
+            "version": CHAT_MESSAGE_SCHEMA_VERSION + 1,
+            "id": "server-2",
+            "msg": {"Leave": {"username": "bob", "room_name": "test-room"}},
+        });
+        let decoded = decode_payload(&serde_json::to_vec(&newer).unwrap()).unwrap();
+        assert!(matches!(
+            decoded.msg,
+            ChatMessagePayload::Known(ChatMessage::Leave { .. })
+        ));
     }
 }
@@ -0,0 +1,222 @@
+use super::chat_message::IdLabeledMessage;
+use crate::{Error, Result};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// First byte of every framed payload.
+///
+/// JSON payloads are bare JSON objects and therefore always start with `{`.
+const HEADER_MAGIC: u8 = 0x00;
+/// Version of the header layout: `[HEADER_MAGIC, HEADER_VERSION, format id]`.
+const HEADER_VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 3;
+
+/// Serialization format of `IdLabeledMessage` payloads on the dragonfly channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PayloadFormat {
+    #[serde(rename = "json")]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    #[serde(rename = "cbor")]
+    Cbor,
+}
+
+impl PayloadFormat {
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Self::Json => 1,
+            Self::MessagePack => 2,
+            Self::Cbor => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Self::Json),
+            2 => Some(Self::MessagePack),
+            3 => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn codec(&self) -> &'static dyn MessageCodec {
+        match self {
+            Self::Json => &JsonCodec,
+            Self::MessagePack => &MessagePackCodec,
+            Self::Cbor => &CborCodec,
+        }
+    }
+}
+
+impl fmt::Display for PayloadFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::MessagePack => write!(f, "msgpack"),
+            Self::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+impl FromStr for PayloadFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Self::Json),
+            "msgpack" => Ok(Self::MessagePack),
+            "cbor" => Ok(Self::Cbor),
+            _ => Err(codec_error(anyhow!("unknown payload format: {}", s))),
+        }
+    }
+}
+
+/// Encodes and decodes the body of a payload, i.e. everything after the header.
+pub trait MessageCodec: Send + Sync {
+    fn format(&self) -> PayloadFormat;
+    fn encode(&self, message: &IdLabeledMessage) -> Result<Vec<u8>>;
+    fn decode(&self, bytes: &[u8]) -> Result<IdLabeledMessage>;
+}
+
+fn codec_error<E: Into<anyhow::Error>>(cause: E) -> Error {
+    Error::CodecError {
+        cause: cause.into(),
+    }
+}
+
+pub struct JsonCodec;
+
+impl MessageCodec for JsonCodec {
+    fn format(&self) -> PayloadFormat {
+        PayloadFormat::Json
+    }
+
+    fn encode(&self, message: &IdLabeledMessage) -> Result<Vec<u8>> {
+        serde_json::to_vec(message).map_err(codec_error)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<IdLabeledMessage> {
+        serde_json::from_slice(bytes).map_err(codec_error)
+    }
+}
+
+pub struct MessagePackCodec;
+
+impl MessageCodec for MessagePackCodec {
+    fn format(&self) -> PayloadFormat {
+        PayloadFormat::MessagePack
+    }
+
+    fn encode(&self, message: &IdLabeledMessage) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(message).map_err(codec_error)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<IdLabeledMessage> {
+        rmp_serde::from_slice(bytes).map_err(codec_error)
+    }
+}
+
+pub struct CborCodec;
+
+impl MessageCodec for CborCodec {
+    fn format(&self) -> PayloadFormat {
+        PayloadFormat::Cbor
+    }
+
+    fn encode(&self, message: &IdLabeledMessage) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(message, &mut bytes).map_err(codec_error)?;
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<IdLabeledMessage> {
+        ciborium::de::from_reader(bytes).map_err(codec_error)
+    }
+}
+
+/// Encodes a message with the given codec, prefixed with the payload header.
+///
+/// JSON payloads are written without a header, as servers did before the other formats, so that
+/// those servers keep reading them during a rolling upgrade.
+pub fn encode_payload(codec: &dyn MessageCodec, message: &IdLabeledMessage) -> Result<Vec<u8>> {
+    let body = codec.encode(message)?;
+    if codec.format() == PayloadFormat::Json {
+        return Ok(body);
+    }
+    let mut payload = Vec::with_capacity(HEADER_LENGTH + body.len());
+    payload.extend_from_slice(&[HEADER_MAGIC, HEADER_VERSION, codec.format().id()]);
+    payload.extend_from_slice(&body);
+    Ok(payload)
+}
+
+/// Decodes a payload written by `encode_payload` in any format, headerless JSON included.
+///
+/// This lets servers configured with different formats share a channel during a rolling upgrade.
+pub fn decode_payload(payload: &[u8]) -> Result<IdLabeledMessage> {
+    match payload {
+        [HEADER_MAGIC, HEADER_VERSION, id, body @ ..] => match PayloadFormat::from_id(*id) {
+            Some(format) => format.codec().decode(body),
+            None => Err(codec_error(anyhow!("unknown payload format id: {}", id))),
+        },
+        [HEADER_MAGIC, version, ..] => Err(codec_error(anyhow!(
+            "unsupported payload header version: {}",
+            version
+        ))),
+        _ => JsonCodec.decode(payload),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{ChatMessage, RoomMessage, ServerId};
+
+    fn message() -> IdLabeledMessage {
+        IdLabeledMessage::new(
+            ServerId::new(),
+            RoomMessage::new(
+                1,
+                ChatMessage::Chat {
+                    username: "alice".to_owned(),
+                    room_name: "test-room".to_owned(),
+                    context: "hello".to_owned(),
+                },
+            ),
+        )
+    }
+
+    #[test]
+    fn test_round_trip_all_formats() {
+        let message = message();
+        for format in [
+            PayloadFormat::Json,
+            PayloadFormat::MessagePack,
+            PayloadFormat::Cbor,
+        ] {
+            let payload = encode_payload(format.codec(), &message).unwrap();
+            if format == PayloadFormat::Json {
+                assert_eq!(payload, serde_json::to_vec(&message).unwrap());
+            } else {
+                assert_eq!(&payload[..HEADER_LENGTH], &[0, 1, format.id()]);
+            }
+            assert_eq!(decode_payload(&payload).unwrap(), message);
+            assert_eq!(format.to_string().parse::<PayloadFormat>().unwrap(), format);
+        }
+    }
+
+    #[test]
+    fn test_decode_framed_json() {
+        let message = message();
+        let mut framed = vec![HEADER_MAGIC, HEADER_VERSION, PayloadFormat::Json.id()];
+        framed.extend_from_slice(&serde_json::to_vec(&message).unwrap());
+        assert_eq!(decode_payload(&framed).unwrap(), message);
+    }
+
+    #[test]
+    fn test_decode_unknown_header() {
+        assert!(decode_payload(&[HEADER_MAGIC, HEADER_VERSION, 42]).is_err());
+        assert!(decode_payload(&[HEADER_MAGIC, 2, 1, b'{', b'}']).is_err());
+    }
+}
@@ -0,0 +1,31 @@
+use super::chat_message::ChatMessage;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A `ChatMessage` stored in the history of its room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomMessage {
+    /// Sequence number assigned by the server which received the message, unique within a room.
+    pub id: u64,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: i64,
+    pub msg: ChatMessage,
+}
+
+/// Milliseconds since the unix epoch.
+pub(crate) fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+impl RoomMessage {
+    pub fn new(id: u64, msg: ChatMessage) -> Self {
+        Self {
+            id,
+            timestamp: unix_millis(),
+            msg,
+        }
+    }
+}
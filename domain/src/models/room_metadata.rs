@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Who can join a room.
+///
+/// Invited users and the owner can join any room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomAccess {
+    #[default]
+    Public,
+    /// Only invited users can join.
+    Invite,
+    /// Users knowing the password of the room can join.
+    Password,
+}
+
+impl fmt::Display for RoomAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Public => write!(f, "public"),
+            Self::Invite => write!(f, "invite"),
+            Self::Password => write!(f, "password"),
+        }
+    }
+}
+
+impl FromStr for RoomAccess {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Self::Public),
+            "invite" => Ok(Self::Invite),
+            "password" => Ok(Self::Password),
+            _ => Err(format!("unknown room access {:?}", s)),
+        }
+    }
+}
+
+/// Properties of a room, besides its members and messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomMetadata {
+    pub room_name: String,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    /// Milliseconds since the unix epoch at which the room was created.
+    pub created_at: Option<i64>,
+    /// Username of the first user who joined the room, or name of the integration which created it.
+    pub owner: Option<String>,
+    /// Number of users the room accepts, unlimited when `None`.
+    pub max_members: Option<usize>,
+    /// Archived rooms keep their history but can't be joined anymore.
+    #[serde(default)]
+    pub archived: bool,
+    /// Ephemeral rooms are deleted once their last user leaves.
+    ///
+    /// Only rooms created as such through the directory are ephemeral; rooms created implicitly by
+    /// joining them are persistent.
+    #[serde(default)]
+    pub ephemeral: bool,
+    #[serde(default)]
+    pub access: RoomAccess,
+}
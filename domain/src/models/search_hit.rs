@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+/// A chat message matching a search.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchHit {
+    /// `RoomMessage::id` of the message.
+    pub message_id: u64,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: i64,
+    pub username: String,
+    /// Excerpt of the message around the first matching term.
+    pub snippet: String,
+}
@@ -3,7 +3,7 @@ use std::fmt;
 use std::ops::Deref;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ServerId(String);
 
 impl ServerId {
@@ -13,6 +13,12 @@ impl ServerId {
     }
 }
 
+impl Default for ServerId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AsRef<str> for ServerId {
     fn as_ref(&self) -> &str {
         &self.0
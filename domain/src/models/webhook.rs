@@ -0,0 +1,68 @@
+use super::chat_message::ChatMessage;
+use super::room_message::RoomMessage;
+use serde::{Deserialize, Serialize};
+
+/// Kind of room event a webhook can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookEvent {
+    Join,
+    Leave,
+    Chat,
+    Rename,
+    Topic,
+    Invite,
+    Read,
+}
+
+impl WebhookEvent {
+    pub fn of(msg: &ChatMessage) -> Self {
+        match msg {
+            ChatMessage::Join { .. } => Self::Join,
+            ChatMessage::Leave { .. } => Self::Leave,
+            ChatMessage::Chat { .. } | ChatMessage::Action { .. } => Self::Chat,
+            ChatMessage::Rename { .. } => Self::Rename,
+            ChatMessage::SetTopic { .. } => Self::Topic,
+            ChatMessage::Invite { .. } => Self::Invite,
+            ChatMessage::ReadUpTo { .. } => Self::Read,
+        }
+    }
+}
+
+/// Endpoint receiving the events of a room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookSubscription {
+    pub id: String,
+    pub room_name: String,
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature sent with every delivery.
+    pub secret: String,
+    /// Events to deliver, all of them when empty.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+impl WebhookSubscription {
+    pub fn accepts(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+/// JSON body posted to webhook endpoints.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    pub event: WebhookEvent,
+    pub message: RoomMessage,
+}
+
+/// Delivery which still failed after all its attempts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub subscription_id: String,
+    pub url: String,
+    pub payload: String,
+    pub attempts: u32,
+    pub error: String,
+    /// Milliseconds since the unix epoch.
+    pub failed_at: i64,
+}
@@ -1 +1,9 @@
+mod channel;
 pub mod chat_room;
+pub mod read_receipts;
+pub mod room_access;
+pub mod room_directory;
+pub mod room_history;
+pub mod room_metadata;
+pub mod room_search;
+pub mod webhook;
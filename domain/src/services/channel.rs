@@ -0,0 +1,43 @@
+//! Consumption of the PUB/SUB channel shared by the servers of a cluster.
+
+use crate::storage::Storage;
+use crate::Result;
+use std::time::Duration;
+
+/// Delay before subscribing again after a failure, doubled after each failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Hands every payload of the channel to `handle`; never returns.
+///
+/// A subscription which breaks (or can't be made, e.g. during a failover) is made again with a
+/// fresh connection of the pool after a backoff; payloads published in the meantime are lost.
+/// `on_subscribe` runs after each subscription.
+pub(crate) fn consume(
+    storage: &Storage,
+    channel_name: &str,
+    mut on_subscribe: impl FnMut(),
+    mut handle: impl FnMut(Vec<u8>),
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let result = storage.get().and_then(|mut connection| -> Result<()> {
+            let mut subscription = connection.subscribe(channel_name)?;
+            backoff = INITIAL_BACKOFF;
+            on_subscribe();
+            loop {
+                handle(subscription.next_payload()?);
+            }
+        });
+        if let Err(e) = result {
+            tracing::error!(
+                "subscription to {} failed, subscribing again in {:?}: {}",
+                channel_name,
+                backoff,
+                e
+            );
+        }
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
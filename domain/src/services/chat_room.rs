@@ -1,51 +1,119 @@
+use super::{room_access, room_directory, room_metadata};
+use crate::storage::{Connection, JoinOutcome, RenameOutcome, Storage};
 use crate::{models, Result};
-use dragonfly::{RedisConnection, RedisPool};
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
 use tokio::sync::broadcast;
 
-fn is_username_member(
-    redis_connection: &mut RedisConnection,
-    room_name: &str,
-    username: &str,
-) -> Result<bool> {
-    dragonfly::adapters::sismember(redis_connection, room_name, username).map_err(Into::into)
+/// Returns the usernames in the room, sorted.
+pub fn room_members(connection: &mut dyn Connection, room_name: &str) -> Result<Vec<String>> {
+    connection.members(room_name)
 }
 
-fn add_username_to_room(
-    redis_connection: &mut RedisConnection,
-    room_name: &str,
-    username: &str,
-) -> Result<()> {
-    dragonfly::adapters::sadd(redis_connection, room_name, username).map_err(Into::into)
+pub fn room_member_count(connection: &mut dyn Connection, room_name: &str) -> Result<usize> {
+    connection.member_count(room_name)
 }
 
-fn remove_username_from_room(
-    redis_connection: &mut RedisConnection,
-    room_name: &str,
-    username: &str,
-) -> Result<()> {
-    dragonfly::adapters::srem(redis_connection, room_name, username).map_err(Into::into)
+/// Why a user can't join a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinRejection {
+    /// Another user holds the username (or the identity token doesn't match).
+    UsernameTaken,
+    /// The room already has `RoomMetadata::max_members` users.
+    RoomFull,
+    RoomArchived,
+    Denied(room_access::AccessDenial),
 }
 
+/// Why a user can't change its username.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameRejection {
+    UsernameTaken,
+    /// The user holds other sessions in the room, which would keep the current username.
+    OtherSessions,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JoinOptions<'a> {
+    /// Password of a password protected room, not needed by invited users.
+    pub password: Option<&'a str>,
+    /// Identity token of a session the user holds in the room, to join it from another device.
+    ///
+    /// The identity token of the user who created the room also proves it is the owner.
+    pub identity_token: Option<&'a str>,
+    /// Token given by `room_access::invite` to the user.
+    pub invite_token: Option<&'a str>,
+}
+
+/// A session of a user in a room.
+///
+/// A user may hold several sessions in a room (e.g. from several devices): the first one reserves
+/// the username and gets an identity token, which other sessions present to join under the same
+/// username. The username is released when the last session leaves.
 pub struct ChatRoomUser {
-    redis_pool: RedisPool,
+    storage: Storage,
     room_name: String,
     username: String,
+    identity_token: String,
+    first_session: bool,
+    owner: bool,
+    left: bool,
+    room_deleted: bool,
 }
 
 impl ChatRoomUser {
-    pub fn try_new(redis_pool: RedisPool, room_name: &str, username: &str) -> Result<Option<Self>> {
-        let mut redis_connection = redis_pool.get()?;
-        if is_username_member(&mut redis_connection, room_name, username)? {
-            Ok(None)
-        } else {
-            add_username_to_room(&mut redis_connection, room_name, username)?;
-            Ok(Some(Self {
-                redis_pool,
-                room_name: room_name.to_owned(),
-                username: username.to_owned(),
-            }))
+    /// Opens a session of the user in the room.
+    ///
+    /// Joining a room which doesn't exist creates it as a persistent room owned by the user, who
+    /// proves it later with its identity token.
+    pub fn try_new(
+        storage: Storage,
+        room_name: &str,
+        username: &str,
+        options: JoinOptions,
+    ) -> Result<core::result::Result<Self, JoinRejection>> {
+        let mut connection = storage.get()?;
+        let metadata = room_metadata::room_metadata(&mut connection, room_name)?;
+        if metadata.archived {
+            return Ok(Err(JoinRejection::RoomArchived));
+        }
+        if let Err(denial) =
+            room_access::check_access(&mut connection, &metadata, username, &options)?
+        {
+            return Ok(Err(JoinRejection::Denied(denial)));
         }
+        // A token presented for a username nobody holds becomes the identity of the user.
+        let identity_token = match options.identity_token {
+            Some(identity_token) => identity_token.to_owned(),
+            None => uuid::Uuid::new_v4().simple().to_string(),
+        };
+        let first_session = match connection.join_room(
+            room_name,
+            username,
+            &room_access::token_digest(&identity_token),
+            metadata.max_members,
+        )? {
+            JoinOutcome::FirstSession => true,
+            JoinOutcome::OtherSession => false,
+            JoinOutcome::UsernameTaken => return Ok(Err(JoinRejection::UsernameTaken)),
+            JoinOutcome::RoomFull => return Ok(Err(JoinRejection::RoomFull)),
+        };
+        if first_session
+            && room_directory::create_room(&mut connection, room_name, username, false)?
+        {
+            room_access::set_owner_identity(&mut connection, room_name, &identity_token)?;
+        }
+        let owner = room_access::is_owner_identity(&mut connection, room_name, &identity_token)?;
+        Ok(Ok(Self {
+            storage,
+            room_name: room_name.to_owned(),
+            username: username.to_owned(),
+            identity_token,
+            first_session,
+            owner,
+            left: false,
+            room_deleted: false,
+        }))
     }
 
     pub fn room_name(&self) -> &str {
@@ -55,88 +123,594 @@ impl ChatRoomUser {
     pub fn username(&self) -> &str {
         &self.username
     }
+
+    /// Token to give to the other sessions of the user.
+    pub fn identity_token(&self) -> &str {
+        &self.identity_token
+    }
+
+    /// Whether the user wasn't in the room before this session.
+    pub fn is_first_session(&self) -> bool {
+        self.first_session
+    }
+
+    /// Whether the user proved to be the owner of the room with its identity token.
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
+    /// Whether leaving deleted the room, an ephemeral room left by its last user.
+    ///
+    /// Nothing is to be sent to a deleted room anymore, which would recreate its history.
+    pub fn is_room_deleted(&self) -> bool {
+        self.room_deleted
+    }
+
+    /// Changes the username held in the room.
+    ///
+    /// The new username is claimed before the current one is released, so that no other user can
+    /// take either of them in between. The current username is kept when the change is rejected.
+    pub fn rename(
+        &mut self,
+        new_username: &str,
+    ) -> Result<core::result::Result<(), RenameRejection>> {
+        let mut connection = self.storage.get()?;
+        match connection.rename_member(&self.room_name, &self.username, new_username)? {
+            RenameOutcome::Renamed => {
+                self.username = new_username.to_owned();
+                Ok(Ok(()))
+            }
+            RenameOutcome::UsernameTaken => Ok(Err(RenameRejection::UsernameTaken)),
+            RenameOutcome::OtherSessions => Ok(Err(RenameRejection::OtherSessions)),
+        }
+    }
+
+    /// Closes the session. Returns whether it was the last session of the user, who left the room;
+    /// `false` when the room was deleted under the session, as there is nothing left to leave.
+    ///
+    /// Dropping the session closes it too; calling this again does nothing.
+    pub fn leave(&mut self) -> Result<bool> {
+        if self.left {
+            return Ok(false);
+        }
+        self.left = true;
+        let mut connection = self.storage.get()?;
+        if !connection.leave_room(&self.room_name, &self.username)? {
+            return Ok(false);
+        }
+        self.room_deleted =
+            room_directory::cleanup_ephemeral_room(&mut connection, &self.room_name)?;
+        Ok(true)
+    }
 }
 
 impl Drop for ChatRoomUser {
     fn drop(&mut self) {
-        let mut redis_connection = self.redis_pool.get().unwrap();
-        remove_username_from_room(&mut redis_connection, &self.room_name, &self.username).unwrap();
+        if let Err(e) = self.leave() {
+            tracing::error!("failed to leave {}: {}", self.room_name, e);
+        }
     }
 }
 
 pub struct ChatRoomPublisherService {
-    redis_pool: RedisPool,
+    storage: Storage,
     server_id: models::ServerId,
     channel_name: String,
-    broadcaster: broadcast::Sender<String>,
+    payload_format: models::PayloadFormat,
+    broadcaster: broadcast::Sender<models::RoomMessage>,
     receiver: mpsc::Receiver<models::ChatMessage>,
 }
 
 impl ChatRoomPublisherService {
     pub fn new<S: Into<models::ServerId>>(
-        redis_pool: RedisPool,
+        storage: Storage,
         server_id: S,
         channel_name: String,
-        broadcaster: broadcast::Sender<String>,
+        payload_format: models::PayloadFormat,
+        broadcaster: broadcast::Sender<models::RoomMessage>,
         receiver: mpsc::Receiver<models::ChatMessage>,
     ) -> Self {
         Self {
-            redis_pool,
+            storage,
             server_id: server_id.into(),
             channel_name,
+            payload_format,
             broadcaster,
             receiver,
         }
     }
+    /// Publishes the messages until every sender is dropped.
+    ///
+    /// A message which can't be stored or published is logged and dropped; the next one is sent
+    /// with a fresh connection of the pool.
     pub fn start(self) {
-        let mut redis_connection = self.redis_pool.get().unwrap();
-        for message in self.receiver {
-            let _ = self.broadcaster.send(message.message_context());
-            dragonfly::adapters::publish(
-                &mut redis_connection,
-                &self.channel_name,
-                models::IdLabeledMessage {
-                    id: self.server_id.clone(),
-                    msg: message,
-                },
-            )
-            .unwrap();
+        for msg in &self.receiver {
+            let room_name = msg.room_name().to_owned();
+            if let Err(e) = self.publish(msg) {
+                tracing::error!("failed to publish a message of {}: {}", room_name, e);
+            }
         }
     }
+
+    fn publish(&self, msg: models::ChatMessage) -> Result<()> {
+        let mut connection = self.storage.get()?;
+        let message = if msg.is_stored() {
+            super::room_history::append_message(&mut connection, msg)?
+        } else {
+            // Carries the id of the latest stored message, which stays a valid cursor.
+            let id = super::room_history::last_message_id(&mut connection, msg.room_name())?;
+            models::RoomMessage::new(id, msg)
+        };
+        let _ = self.broadcaster.send(message.clone());
+        let payload = models::encode_payload(
+            self.payload_format.codec(),
+            &models::IdLabeledMessage::new(self.server_id.clone(), message),
+        )?;
+        connection.publish(&self.channel_name, &payload)
+    }
+}
+
+/// Counters of a `ChatRoomSubscriberService`: its subscriptions and the messages it could not
+/// deliver.
+#[derive(Debug, Default)]
+pub struct SubscriberStats {
+    subscriptions: AtomicU64,
+    undecodable_payloads: AtomicU64,
+    unknown_messages: AtomicU64,
+}
+
+impl SubscriberStats {
+    /// Subscriptions to the channel made so far, one more after each failure; the service receives
+    /// messages once it is one.
+    pub fn subscriptions(&self) -> u64 {
+        self.subscriptions.load(Ordering::Relaxed)
+    }
+
+    /// Payloads which could not be decoded at all (unknown format or broken envelope).
+    pub fn undecodable_payloads(&self) -> u64 {
+        self.undecodable_payloads.load(Ordering::Relaxed)
+    }
+
+    /// Envelopes carrying a `ChatMessage` variant unknown to this build.
+    pub fn unknown_messages(&self) -> u64 {
+        self.unknown_messages.load(Ordering::Relaxed)
+    }
 }
 
 pub struct ChatRoomSubscriberService {
-    redis_pool: RedisPool,
+    storage: Storage,
     server_id: models::ServerId,
     channel_name: String,
-    broadcaster: broadcast::Sender<String>,
+    broadcaster: broadcast::Sender<models::RoomMessage>,
+    stats: Arc<SubscriberStats>,
 }
 
 impl ChatRoomSubscriberService {
     pub fn new<S: Into<models::ServerId>>(
-        redis_pool: RedisPool,
+        storage: Storage,
         server_id: S,
         channel_name: String,
-        broadcaster: broadcast::Sender<String>,
+        broadcaster: broadcast::Sender<models::RoomMessage>,
     ) -> Self {
         Self {
-            redis_pool,
+            storage,
             server_id: server_id.into(),
             channel_name,
             broadcaster,
+            stats: Arc::new(SubscriberStats::default()),
         }
     }
 
+    pub fn stats(&self) -> Arc<SubscriberStats> {
+        self.stats.clone()
+    }
+
+    /// Broadcasts the messages of the other servers, subscribing again after a failure.
     pub fn start(self) {
-        let mut redis_connection = self.redis_pool.get().unwrap();
-        let mut pub_sub =
-            dragonfly::adapters::subscribe(&mut redis_connection, &self.channel_name).unwrap();
-        while let Ok(msg) = pub_sub.get_message() {
-            if let Ok(message) = msg.get_payload::<models::IdLabeledMessage>() {
-                if message.id != self.server_id {
-                    let _ = self.broadcaster.send(message.msg.message_context());
+        super::channel::consume(
+            &self.storage,
+            &self.channel_name,
+            || {
+                self.stats.subscriptions.fetch_add(1, Ordering::Relaxed);
+            },
+            |payload| self.receive(&payload),
+        );
+    }
+
+    fn receive(&self, payload: &[u8]) {
+        let message = match models::decode_payload(payload) {
+            Ok(message) => message,
+            Err(e) => {
+                let count = self
+                    .stats
+                    .undecodable_payloads
+                    .fetch_add(1, Ordering::Relaxed)
+                    + 1;
+                tracing::warn!("dropped undecodable payload ({} so far): {}", count, e);
+                return;
+            }
+        };
+        if message.id == self.server_id {
+            return;
+        }
+        match message.msg {
+            models::ChatMessagePayload::Known(msg) => {
+                let _ = self.broadcaster.send(models::RoomMessage {
+                    id: message.message_id,
+                    timestamp: message.timestamp,
+                    msg,
+                });
+            }
+            models::ChatMessagePayload::Unknown(value) => {
+                let count = self.stats.unknown_messages.fetch_add(1, Ordering::Relaxed) + 1;
+                tracing::warn!(
+                    "dropped unknown message with schema version {} from {} ({} so far): {}",
+                    message.version,
+                    message.id,
+                    count,
+                    value
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::services::read_receipts;
+    use crate::storage::{Backend, MemoryBackend};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+
+    /// Memory backend failing to hand out its first `failures` connections.
+    struct FlakyBackend {
+        backend: MemoryBackend,
+        failures: AtomicUsize,
+    }
+
+    impl Backend for FlakyBackend {
+        fn connection(&self, keys: &crate::keys::Keys) -> Result<Box<dyn Connection>> {
+            let failing = self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |failures| {
+                    failures.checked_sub(1)
+                })
+                .is_ok();
+            if failing {
+                return Err(crate::Error::SystemError {
+                    cause: anyhow::anyhow!("connection refused"),
+                });
+            }
+            self.backend.connection(keys)
+        }
+    }
+
+    /// Waits a while for the next message of the broadcaster.
+    fn next_broadcast(
+        receiver: &mut broadcast::Receiver<models::RoomMessage>,
+    ) -> Option<models::RoomMessage> {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            match receiver.try_recv() {
+                Ok(message) => return Some(message),
+                Err(broadcast::error::TryRecvError::Empty) => {
+                    std::thread::sleep(Duration::from_millis(10))
                 }
+                Err(_) => return None,
             }
         }
+        None
+    }
+
+    /// Waits a while for the subscriber to have subscribed `count` times.
+    fn wait_for_subscriptions(stats: &SubscriberStats, count: u64) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while stats.subscriptions() < count {
+            assert!(Instant::now() < deadline, "not subscribed in time");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_rename() {
+        let storage = Storage::memory();
+        let room_name = format!("rename-test-{}", models::ServerId::new());
+        let mut alice =
+            ChatRoomUser::try_new(storage.clone(), &room_name, "alice", Default::default())
+                .unwrap()
+                .unwrap();
+        let carol = ChatRoomUser::try_new(storage.clone(), &room_name, "carol", Default::default())
+            .unwrap()
+            .unwrap();
+        assert!(alice.is_owner());
+        assert!(!carol.is_owner());
+
+        assert_eq!(
+            alice.rename("carol").unwrap(),
+            Err(RenameRejection::UsernameTaken)
+        );
+        assert_eq!(alice.username(), "alice");
+        let mut connection = storage.get().unwrap();
+        crate::services::room_history::append_message(
+            &mut connection,
+            models::ChatMessage::Join {
+                username: "carol".to_owned(),
+                room_name: room_name.clone(),
+            },
+        )
+        .unwrap();
+        read_receipts::mark_read(&mut connection, &room_name, "alice", 1).unwrap();
+        assert_eq!(alice.rename("bob").unwrap(), Ok(()));
+        assert_eq!(alice.username(), "bob");
+        assert_eq!(
+            room_members(&mut connection, &room_name).unwrap(),
+            vec!["bob", "carol"]
+        );
+        // The read receipt follows the user.
+        assert_eq!(
+            read_receipts::last_read(&mut connection, &room_name, "bob").unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            read_receipts::last_read(&mut connection, &room_name, "alice").unwrap(),
+            None
+        );
+        assert!(read_receipts::unread_counts(&mut connection, "bob")
+            .unwrap()
+            .contains_key(&room_name));
+        assert!(read_receipts::unread_counts(&mut connection, "alice")
+            .unwrap()
+            .is_empty());
+        assert!(
+            ChatRoomUser::try_new(storage, &room_name, "alice", Default::default())
+                .unwrap()
+                .is_ok()
+        );
+
+        drop(alice);
+        drop(carol);
+        assert!(room_members(&mut connection, &room_name)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_join_rejections() {
+        let storage = Storage::memory();
+        let room_name = format!("join-test-{}", models::ServerId::new());
+        let alice = ChatRoomUser::try_new(storage.clone(), &room_name, "alice", Default::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            ChatRoomUser::try_new(storage.clone(), &room_name, "alice", Default::default())
+                .unwrap()
+                .err(),
+            Some(JoinRejection::UsernameTaken)
+        );
+
+        let mut connection = storage.get().unwrap();
+        room_metadata::set_max_members(&mut connection, &room_name, Some(1)).unwrap();
+        assert_eq!(
+            ChatRoomUser::try_new(storage.clone(), &room_name, "bob", Default::default())
+                .unwrap()
+                .err(),
+            Some(JoinRejection::RoomFull)
+        );
+        assert_eq!(room_member_count(&mut connection, &room_name).unwrap(), 1);
+        let metadata = room_metadata::room_metadata(&mut connection, &room_name).unwrap();
+        assert_eq!(metadata.owner.as_deref(), Some("alice"));
+
+        drop(alice);
+        assert!(
+            ChatRoomUser::try_new(storage, &room_name, "bob", Default::default())
+                .unwrap()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_multiple_sessions() {
+        let storage = Storage::memory();
+        let room_name = format!("sessions-test-{}", models::ServerId::new());
+        let mut laptop =
+            ChatRoomUser::try_new(storage.clone(), &room_name, "alice", Default::default())
+                .unwrap()
+                .unwrap();
+        assert!(laptop.is_first_session());
+        assert!(laptop.is_owner());
+        let options = JoinOptions {
+            identity_token: Some("forged"),
+            ..Default::default()
+        };
+        assert_eq!(
+            ChatRoomUser::try_new(storage.clone(), &room_name, "alice", options)
+                .unwrap()
+                .err(),
+            Some(JoinRejection::UsernameTaken)
+        );
+        let identity_token = laptop.identity_token().to_owned();
+        let options = JoinOptions {
+            identity_token: Some(&identity_token),
+            ..Default::default()
+        };
+        let mut phone = ChatRoomUser::try_new(storage.clone(), &room_name, "alice", options)
+            .unwrap()
+            .unwrap();
+        assert!(!phone.is_first_session());
+        assert!(phone.is_owner());
+        assert_eq!(phone.identity_token(), identity_token);
+        assert_eq!(
+            laptop.rename("bob").unwrap(),
+            Err(RenameRejection::OtherSessions)
+        );
+
+        let mut connection = storage.get().unwrap();
+        assert!(!laptop.leave().unwrap());
+        assert!(!laptop.leave().unwrap());
+        assert_eq!(
+            room_members(&mut connection, &room_name).unwrap(),
+            vec!["alice"]
+        );
+        assert_eq!(phone.rename("bob").unwrap(), Ok(()));
+        let options = JoinOptions {
+            identity_token: Some(&identity_token),
+            ..Default::default()
+        };
+        let mut tablet = ChatRoomUser::try_new(storage.clone(), &room_name, "bob", options)
+            .unwrap()
+            .unwrap();
+        assert!(!tablet.leave().unwrap());
+        assert!(phone.leave().unwrap());
+        assert!(room_members(&mut connection, &room_name)
+            .unwrap()
+            .is_empty());
+        assert!(ChatRoomUser::try_new(storage, &room_name, "bob", options)
+            .unwrap()
+            .unwrap()
+            .is_first_session());
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_services_relay_between_servers() {
+        let storage = Storage::memory();
+        let channel_name = "relay-test".to_owned();
+        let room_name = format!("relay-test-{}", models::ServerId::new());
+        let (broadcaster, mut local) = broadcast::channel(16);
+        let (remote_broadcaster, mut remote) = broadcast::channel(16);
+        let (publisher, receiver) = mpsc::sync_channel(16);
+        let server_id = models::ServerId::new();
+        let subscribers = [
+            (server_id.clone(), broadcaster.clone()),
+            (models::ServerId::new(), remote_broadcaster),
+        ];
+        for (server_id, broadcaster) in subscribers {
+            let service = ChatRoomSubscriberService::new(
+                storage.clone(),
+                server_id,
+                channel_name.clone(),
+                broadcaster,
+            );
+            let stats = service.stats();
+            std::thread::spawn(move || service.start());
+            // Subscribes before anything is published.
+            wait_for_subscriptions(&stats, 1);
+        }
+        let service = ChatRoomPublisherService::new(
+            storage.clone(),
+            server_id,
+            channel_name,
+            models::PayloadFormat::Json,
+            broadcaster,
+            receiver,
+        );
+        std::thread::spawn(move || service.start());
+
+        let msg = models::ChatMessage::Chat {
+            username: "alice".to_owned(),
+            room_name: room_name.clone(),
+            context: "hello".to_owned(),
+        };
+        publisher.send(msg.clone()).unwrap();
+        let message = next_broadcast(&mut local).unwrap();
+        assert_eq!(message.msg, msg);
+        assert_eq!(next_broadcast(&mut remote), Some(message.clone()));
+        // The server which received the message doesn't broadcast it twice.
+        assert!(next_broadcast(&mut local).is_none());
+
+        // Read receipts are relayed without being stored.
+        let read = models::ChatMessage::ReadUpTo {
+            username: "bob".to_owned(),
+            room_name: room_name.clone(),
+            message_id: message.id,
+        };
+        publisher.send(read.clone()).unwrap();
+        let receipt = next_broadcast(&mut local).unwrap();
+        assert_eq!((receipt.id, &receipt.msg), (message.id, &read));
+        assert_eq!(next_broadcast(&mut remote), Some(receipt));
+
+        let mut connection = storage.get().unwrap();
+        let history =
+            crate::services::room_history::messages_after(&mut connection, &room_name, 0, 10)
+                .unwrap();
+        assert_eq!(history, vec![message]);
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_publisher_survives_storage_failures() {
+        let storage = Storage::new(FlakyBackend {
+            backend: MemoryBackend::default(),
+            failures: AtomicUsize::new(1),
+        });
+        let room_name = format!("publisher-test-{}", models::ServerId::new());
+        let (broadcaster, mut local) = broadcast::channel(16);
+        let (publisher, receiver) = mpsc::sync_channel(16);
+        let service = ChatRoomPublisherService::new(
+            storage,
+            models::ServerId::new(),
+            "publisher-test".to_owned(),
+            models::PayloadFormat::Json,
+            broadcaster,
+            receiver,
+        );
+        let handle = std::thread::spawn(move || service.start());
+
+        for context in ["lost", "sent"] {
+            let msg = models::ChatMessage::Chat {
+                username: "alice".to_owned(),
+                room_name: room_name.clone(),
+                context: context.to_owned(),
+            };
+            publisher.send(msg).unwrap();
+        }
+        let message = next_broadcast(&mut local).unwrap();
+        assert_eq!(message.msg.message_context(), "alice: sent");
+        drop(publisher);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_subscriber_survives_storage_failures() {
+        let storage = Storage::new(FlakyBackend {
+            backend: MemoryBackend::default(),
+            failures: AtomicUsize::new(2),
+        });
+        let (broadcaster, mut local) = broadcast::channel(16);
+        let service = ChatRoomSubscriberService::new(
+            storage.clone(),
+            models::ServerId::new(),
+            "subscriber-test".to_owned(),
+            broadcaster,
+        );
+        let stats = service.stats();
+        std::thread::spawn(move || service.start());
+        // Subscribes with the third connection, after backing off twice.
+        wait_for_subscriptions(&stats, 1);
+
+        let msg = models::ChatMessage::Chat {
+            username: "alice".to_owned(),
+            room_name: "subscriber-test".to_owned(),
+            context: "hello".to_owned(),
+        };
+        let payload = models::encode_payload(
+            models::PayloadFormat::Json.codec(),
+            &models::IdLabeledMessage::new(
+                models::ServerId::new(),
+                models::RoomMessage::new(1, msg.clone()),
+            ),
+        )
+        .unwrap();
+        storage
+            .get()
+            .unwrap()
+            .publish("subscriber-test", &payload)
+            .unwrap();
+        assert_eq!(next_broadcast(&mut local).unwrap().msg, msg);
     }
 }
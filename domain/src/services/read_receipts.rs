@@ -0,0 +1,135 @@
+use super::room_history;
+use crate::storage::Connection;
+use crate::{models, Result};
+use std::collections::BTreeMap;
+
+/// Returns the id of the last message the user read in the room, if the user ever read it.
+pub fn last_read(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    username: &str,
+) -> Result<Option<u64>> {
+    connection.last_read(room_name, username)
+}
+
+/// Records that the user read the room up to the message, or up to the latest message if the id
+/// is greater.
+///
+/// Receipts never move backwards: returns the new read position, or `None` when the user had
+/// already read that far.
+pub fn mark_read(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    username: &str,
+    message_id: u64,
+) -> Result<Option<u64>> {
+    connection.mark_read(room_name, username, message_id)
+}
+
+/// Counts the chat messages of other users the user didn't read in the room.
+///
+/// Only the messages still kept in the history are counted.
+pub fn unread_count(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    username: &str,
+) -> Result<usize> {
+    let after = last_read(connection, room_name, username)?.unwrap_or_default();
+    let messages = room_history::messages_after(
+        connection,
+        room_name,
+        after,
+        room_history::HISTORY_LENGTH as usize,
+    )?;
+    Ok(messages
+        .iter()
+        .filter(|message| {
+            matches!(
+                &message.msg,
+                models::ChatMessage::Chat { username: author, .. }
+                | models::ChatMessage::Action { username: author, .. } if author != username
+            )
+        })
+        .count())
+}
+
+/// Returns the number of unread messages of the user in every room the user has read receipts of.
+pub fn unread_counts(
+    connection: &mut dyn Connection,
+    username: &str,
+) -> Result<BTreeMap<String, usize>> {
+    let mut unread_counts = BTreeMap::new();
+    for room_name in connection.read_rooms(username)? {
+        if last_read(connection, &room_name, username)?.is_some() {
+            let count = unread_count(connection, &room_name, username)?;
+            unread_counts.insert(room_name, count);
+        }
+    }
+    Ok(unread_counts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::services::room_directory;
+    use crate::storage::Storage;
+
+    fn chat(username: &str, room_name: &str) -> models::ChatMessage {
+        models::ChatMessage::Chat {
+            username: username.to_owned(),
+            room_name: room_name.to_owned(),
+            context: "hello".to_owned(),
+        }
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_read_receipts() {
+        let mut connection = Storage::memory().get().unwrap();
+        let room_name = format!("receipts-test-{}", models::ServerId::new());
+        room_directory::create_room(&mut connection, &room_name, "ci", false).unwrap();
+        for username in ["bob", "alice", "bob", "bob"] {
+            room_history::append_message(&mut connection, chat(username, &room_name)).unwrap();
+        }
+
+        assert_eq!(
+            last_read(&mut connection, &room_name, "alice").unwrap(),
+            None
+        );
+        assert!(!unread_counts(&mut connection, "alice")
+            .unwrap()
+            .contains_key(&room_name));
+        assert_eq!(
+            mark_read(&mut connection, &room_name, "alice", 2).unwrap(),
+            Some(2)
+        );
+        assert_eq!(
+            mark_read(&mut connection, &room_name, "alice", 1).unwrap(),
+            None
+        );
+        assert_eq!(
+            unread_count(&mut connection, &room_name, "alice").unwrap(),
+            2
+        );
+        assert_eq!(
+            unread_counts(&mut connection, "alice")
+                .unwrap()
+                .get(&room_name),
+            Some(&2)
+        );
+        assert_eq!(
+            mark_read(&mut connection, &room_name, "alice", 100).unwrap(),
+            Some(4)
+        );
+        assert_eq!(
+            unread_count(&mut connection, &room_name, "alice").unwrap(),
+            0
+        );
+
+        room_directory::delete_room(&mut connection, &room_name).unwrap();
+        assert_eq!(
+            last_read(&mut connection, &room_name, "alice").unwrap(),
+            None
+        );
+    }
+}
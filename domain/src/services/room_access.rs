@@ -0,0 +1,220 @@
+use super::{chat_room::JoinOptions, room_metadata};
+use crate::storage::Connection;
+use crate::{models, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Digest of an identity or invite token, the only form in which tokens are stored.
+pub(crate) fn token_digest(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Grants access to the room to the user, who joins it with the returned invite token.
+///
+/// Inviting the user again replaces the previous token.
+pub fn invite(connection: &mut dyn Connection, room_name: &str, username: &str) -> Result<String> {
+    let invite_token = uuid::Uuid::new_v4().simple().to_string();
+    connection.invite(room_name, username, &token_digest(&invite_token))?;
+    Ok(invite_token)
+}
+
+/// Makes the user holding the identity token the owner of the room.
+pub(crate) fn set_owner_identity(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    identity_token: &str,
+) -> Result<()> {
+    room_metadata::set_field(
+        connection,
+        room_name,
+        room_metadata::OWNER_IDENTITY,
+        Some(token_digest(identity_token)),
+    )
+}
+
+/// Returns whether the identity token is the one of the user who created the room.
+///
+/// Rooms created by integrations have no owner identity.
+pub fn is_owner_identity(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    identity_token: &str,
+) -> Result<bool> {
+    let owner_identity =
+        room_metadata::get_field(connection, room_name, room_metadata::OWNER_IDENTITY)?;
+    Ok(owner_identity == Some(token_digest(identity_token)))
+}
+
+/// Returns the invited usernames, sorted.
+pub fn invited_usernames(connection: &mut dyn Connection, room_name: &str) -> Result<Vec<String>> {
+    connection.invites(room_name)
+}
+
+fn password_digest(salt: &str, password: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(salt.as_bytes()).unwrap();
+    mac.update(password.as_bytes());
+    mac
+}
+
+/// Sets the password of the room, or removes it with `None`.
+///
+/// Only a salted digest of the password is stored.
+pub fn set_password(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    password: Option<&str>,
+) -> Result<()> {
+    let value = password.map(|password| {
+        let salt = uuid::Uuid::new_v4().simple().to_string();
+        let digest = password_digest(&salt, password).finalize().into_bytes();
+        format!("{}:{}", salt, hex::encode(digest))
+    });
+    room_metadata::set_field(connection, room_name, room_metadata::PASSWORD, value)
+}
+
+fn verify_password(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    password: &str,
+) -> Result<bool> {
+    let value = room_metadata::get_field(connection, room_name, room_metadata::PASSWORD)?;
+    let (salt, digest) = match value.as_deref().and_then(|value| value.split_once(':')) {
+        Some(stored) => stored,
+        None => return Ok(false),
+    };
+    let digest = match hex::decode(digest) {
+        Ok(digest) => digest,
+        Err(_) => return Ok(false),
+    };
+    Ok(password_digest(salt, password)
+        .verify_slice(&digest)
+        .is_ok())
+}
+
+/// Why a user isn't allowed in a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessDenial {
+    NotInvited,
+    WrongPassword,
+}
+
+/// Checks whether the user may join the room with the credentials of `options`.
+///
+/// The owner (proving it with its identity token) and invited users (with their invite token) may
+/// join any room.
+pub fn check_access(
+    connection: &mut dyn Connection,
+    metadata: &models::RoomMetadata,
+    username: &str,
+    options: &JoinOptions,
+) -> Result<core::result::Result<(), AccessDenial>> {
+    let room_name = metadata.room_name.as_str();
+    if metadata.access == models::RoomAccess::Public {
+        return Ok(Ok(()));
+    }
+    if let Some(identity_token) = options.identity_token {
+        if is_owner_identity(connection, room_name, identity_token)? {
+            return Ok(Ok(()));
+        }
+    }
+    if let Some(invite_token) = options.invite_token {
+        if connection.is_invited(room_name, username, &token_digest(invite_token))? {
+            return Ok(Ok(()));
+        }
+    }
+    match (metadata.access, options.password) {
+        (models::RoomAccess::Password, Some(password))
+            if verify_password(connection, room_name, password)? =>
+        {
+            Ok(Ok(()))
+        }
+        (models::RoomAccess::Password, _) => Ok(Err(AccessDenial::WrongPassword)),
+        _ => Ok(Err(AccessDenial::NotInvited)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::Storage;
+
+    fn check(
+        connection: &mut dyn Connection,
+        room_name: &str,
+        username: &str,
+        options: JoinOptions,
+    ) -> core::result::Result<(), AccessDenial> {
+        let metadata = room_metadata::room_metadata(connection, room_name).unwrap();
+        check_access(connection, &metadata, username, &options).unwrap()
+    }
+
+    fn password(password: &str) -> JoinOptions {
+        JoinOptions {
+            password: Some(password),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_check_access() {
+        let mut connection = Storage::memory().get().unwrap();
+        let room_name = format!("access-test-{}", models::ServerId::new());
+        crate::services::room_directory::create_room(&mut connection, &room_name, "alice", false)
+            .unwrap();
+        set_owner_identity(&mut connection, &room_name, "alice-identity").unwrap();
+        let none = JoinOptions::default();
+        assert_eq!(check(&mut connection, &room_name, "bob", none), Ok(()));
+
+        room_metadata::set_access(&mut connection, &room_name, models::RoomAccess::Invite).unwrap();
+        // Claiming the username of the owner isn't enough.
+        assert_eq!(
+            check(&mut connection, &room_name, "alice", none),
+            Err(AccessDenial::NotInvited)
+        );
+        let owner = JoinOptions {
+            identity_token: Some("alice-identity"),
+            ..Default::default()
+        };
+        assert_eq!(check(&mut connection, &room_name, "alice", owner), Ok(()));
+        assert_eq!(
+            check(&mut connection, &room_name, "bob", none),
+            Err(AccessDenial::NotInvited)
+        );
+        let invite_token = invite(&mut connection, &room_name, "bob").unwrap();
+        let invited = JoinOptions {
+            invite_token: Some(&invite_token),
+            ..Default::default()
+        };
+        assert_eq!(check(&mut connection, &room_name, "bob", invited), Ok(()));
+        assert_eq!(
+            check(&mut connection, &room_name, "bob", none),
+            Err(AccessDenial::NotInvited)
+        );
+        assert_eq!(
+            check(&mut connection, &room_name, "carol", invited),
+            Err(AccessDenial::NotInvited)
+        );
+
+        room_metadata::set_access(&mut connection, &room_name, models::RoomAccess::Password)
+            .unwrap();
+        set_password(&mut connection, &room_name, Some("s3cr3t")).unwrap();
+        assert_eq!(
+            check(&mut connection, &room_name, "carol", none),
+            Err(AccessDenial::WrongPassword)
+        );
+        assert_eq!(
+            check(&mut connection, &room_name, "carol", password("wrong")),
+            Err(AccessDenial::WrongPassword)
+        );
+        assert_eq!(
+            check(&mut connection, &room_name, "carol", password("s3cr3t")),
+            Ok(())
+        );
+        assert_eq!(check(&mut connection, &room_name, "bob", invited), Ok(()));
+        assert_eq!(
+            invited_usernames(&mut connection, &room_name).unwrap(),
+            vec!["bob"]
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use super::room_metadata;
+use crate::storage::Connection;
+use crate::Result;
+
+/// Returns the names of all existing rooms, sorted.
+pub fn room_names(connection: &mut dyn Connection) -> Result<Vec<String>> {
+    connection.room_names()
+}
+
+/// Creates the room unless it exists. Returns whether the room was created.
+pub fn create_room(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    owner: &str,
+    ephemeral: bool,
+) -> Result<bool> {
+    connection.create_room(room_name, owner, ephemeral)
+}
+
+/// Archives the room: its history is kept but users can't join it anymore.
+///
+/// Users in the room at that time stay until they leave. An archived room is never cleaned up.
+pub fn archive_room(connection: &mut dyn Connection, room_name: &str) -> Result<()> {
+    room_metadata::set_archived(connection, room_name, true)
+}
+
+/// Deletes the room with its members, metadata, invites, history, read receipts and webhooks.
+pub fn delete_room(connection: &mut dyn Connection, room_name: &str) -> Result<()> {
+    connection.delete_room(room_name)
+}
+
+/// Deletes the room like `delete_room` unless users are in it. Returns whether it was deleted.
+pub fn delete_empty_room(connection: &mut dyn Connection, room_name: &str) -> Result<bool> {
+    connection.delete_empty_room(room_name)
+}
+
+/// Deletes the room if it is an empty ephemeral room. Returns whether it was deleted.
+pub fn cleanup_ephemeral_room(connection: &mut dyn Connection, room_name: &str) -> Result<bool> {
+    connection.cleanup_room(room_name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::services::{chat_room, room_history};
+    use crate::{models, storage::Storage};
+
+    #[test]
+    #[serial_test::serial]
+    fn test_room_lifecycle() {
+        let storage = Storage::memory();
+        let mut connection = storage.get().unwrap();
+        let persistent = format!("persistent-test-{}", models::ServerId::new());
+        let ephemeral = format!("ephemeral-test-{}", models::ServerId::new());
+
+        assert!(create_room(&mut connection, &ephemeral, "ci", true).unwrap());
+        let mut alice = chat_room::ChatRoomUser::try_new(
+            storage.clone(),
+            &ephemeral,
+            "alice",
+            Default::default(),
+        )
+        .unwrap()
+        .unwrap();
+        room_history::append_message(
+            &mut connection,
+            models::ChatMessage::Join {
+                username: "alice".to_owned(),
+                room_name: ephemeral.clone(),
+            },
+        )
+        .unwrap();
+        // Joining a room which doesn't exist creates a persistent room.
+        let mut bob = chat_room::ChatRoomUser::try_new(
+            storage.clone(),
+            &persistent,
+            "bob",
+            Default::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!create_room(&mut connection, &persistent, "ci", false).unwrap());
+        let names = room_names(&mut connection).unwrap();
+        assert!(names.contains(&persistent));
+        assert!(names.contains(&ephemeral));
+
+        assert!(alice.leave().unwrap());
+        assert!(alice.is_room_deleted());
+        assert!(!room_names(&mut connection).unwrap().contains(&ephemeral));
+        assert!(
+            room_history::messages_after(&mut connection, &ephemeral, 0, 10)
+                .unwrap()
+                .is_empty()
+        );
+        // The ids of the deleted room are not reused.
+        assert_eq!(
+            room_history::last_message_id(&mut connection, &ephemeral).unwrap(),
+            1
+        );
+        assert!(bob.leave().unwrap());
+        assert!(!bob.is_room_deleted());
+        assert!(room_names(&mut connection).unwrap().contains(&persistent));
+        assert!(!cleanup_ephemeral_room(&mut connection, &persistent).unwrap());
+
+        archive_room(&mut connection, &persistent).unwrap();
+        assert_eq!(
+            chat_room::ChatRoomUser::try_new(storage, &persistent, "bob", Default::default())
+                .unwrap()
+                .err(),
+            Some(chat_room::JoinRejection::RoomArchived)
+        );
+        delete_room(&mut connection, &persistent).unwrap();
+        assert!(!room_names(&mut connection).unwrap().contains(&persistent));
+        assert_eq!(
+            room_metadata::room_metadata(&mut connection, &persistent).unwrap(),
+            models::RoomMetadata {
+                room_name: persistent.clone(),
+                ..Default::default()
+            }
+        );
+    }
+}
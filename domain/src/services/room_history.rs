@@ -0,0 +1,79 @@
+use crate::storage::Connection;
+use crate::{models, Result};
+
+/// Number of messages kept in the history of each room.
+pub(crate) const HISTORY_LENGTH: isize = 1000;
+
+/// Assigns the next id of the room to the message and appends it to the room history, indexing
+/// it for search.
+pub fn append_message(
+    connection: &mut dyn Connection,
+    msg: models::ChatMessage,
+) -> Result<models::RoomMessage> {
+    let search_terms = match &msg {
+        models::ChatMessage::Chat { context, .. }
+        | models::ChatMessage::Action {
+            action: context, ..
+        } => super::room_search::terms(context),
+        _ => Default::default(),
+    };
+    connection.append_message(&msg, &search_terms)
+}
+
+/// Returns up to `limit` messages of the room with an id greater than `after`, oldest first.
+///
+/// Messages written by a newer server with a schema unknown to this build are skipped.
+pub fn messages_after(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    after: u64,
+    limit: usize,
+) -> Result<Vec<models::RoomMessage>> {
+    connection.messages_after(room_name, after, limit)
+}
+
+/// Returns the id of the latest message of the room, `0` if nothing was ever sent to it.
+pub fn last_message_id(connection: &mut dyn Connection, room_name: &str) -> Result<u64> {
+    connection.last_message_id(room_name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::Storage;
+
+    #[test]
+    #[serial_test::serial]
+    fn test_append_and_read_messages() {
+        let mut connection = Storage::memory().get().unwrap();
+        let room_name = format!("history-test-{}", models::ServerId::new());
+        let before = last_message_id(&mut connection, &room_name).unwrap();
+        assert_eq!(before, 0);
+
+        for context in ["first", "second", "third"] {
+            append_message(
+                &mut connection,
+                models::ChatMessage::Chat {
+                    username: "alice".to_owned(),
+                    room_name: room_name.clone(),
+                    context: context.to_owned(),
+                },
+            )
+            .unwrap();
+        }
+        assert_eq!(last_message_id(&mut connection, &room_name).unwrap(), 3);
+
+        let messages = messages_after(&mut connection, &room_name, 1, 10).unwrap();
+        let ids: Vec<u64> = messages.iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(
+            messages_after(&mut connection, &room_name, 2, 1)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(messages_after(&mut connection, &room_name, 3, 10)
+            .unwrap()
+            .is_empty());
+    }
+}
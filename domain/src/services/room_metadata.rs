@@ -0,0 +1,157 @@
+use crate::storage::Connection;
+use crate::{models, Result};
+
+const TOPIC: &str = "topic";
+const DESCRIPTION: &str = "description";
+pub(crate) const CREATED_AT: &str = "created_at";
+pub(crate) const OWNER: &str = "owner";
+const MAX_MEMBERS: &str = "max_members";
+pub(crate) const ARCHIVED: &str = "archived";
+pub(crate) const EPHEMERAL: &str = "ephemeral";
+const ACCESS: &str = "access";
+/// Never part of `RoomMetadata`, see `room_access`.
+pub(crate) const PASSWORD: &str = "password";
+/// Never part of `RoomMetadata`, see `room_access`.
+pub(crate) const OWNER_IDENTITY: &str = "owner_identity";
+
+pub fn room_metadata(
+    connection: &mut dyn Connection,
+    room_name: &str,
+) -> Result<models::RoomMetadata> {
+    let mut fields = connection.room_metadata(room_name)?;
+    Ok(models::RoomMetadata {
+        room_name: room_name.to_owned(),
+        topic: fields.remove(TOPIC),
+        description: fields.remove(DESCRIPTION),
+        created_at: fields.get(CREATED_AT).and_then(|value| value.parse().ok()),
+        owner: fields.remove(OWNER),
+        max_members: fields.get(MAX_MEMBERS).and_then(|value| value.parse().ok()),
+        archived: fields.contains_key(ARCHIVED),
+        ephemeral: fields.contains_key(EPHEMERAL),
+        access: fields
+            .get(ACCESS)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default(),
+    })
+}
+
+pub(crate) fn get_field(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    field: &str,
+) -> Result<Option<String>> {
+    Ok(connection.room_metadata(room_name)?.remove(field))
+}
+
+pub(crate) fn set_field(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    field: &str,
+    value: Option<String>,
+) -> Result<()> {
+    connection.set_room_metadata(room_name, field, value.as_deref())
+}
+
+/// Sets the topic of the room, or clears it with `None`.
+pub fn set_topic(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    topic: Option<String>,
+) -> Result<()> {
+    set_field(connection, room_name, TOPIC, topic)
+}
+
+pub fn set_description(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    description: Option<String>,
+) -> Result<()> {
+    set_field(connection, room_name, DESCRIPTION, description)
+}
+
+pub(crate) fn set_archived(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    archived: bool,
+) -> Result<()> {
+    set_field(
+        connection,
+        room_name,
+        ARCHIVED,
+        archived.then(|| "1".to_owned()),
+    )
+}
+
+pub fn set_access(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    access: models::RoomAccess,
+) -> Result<()> {
+    set_field(
+        connection,
+        room_name,
+        ACCESS,
+        (access != models::RoomAccess::Public).then(|| access.to_string()),
+    )
+}
+
+/// Limits the number of users in the room, or removes the limit with `None`.
+///
+/// Users already in the room stay when the limit is lowered below their number.
+pub fn set_max_members(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    max_members: Option<usize>,
+) -> Result<()> {
+    set_field(
+        connection,
+        room_name,
+        MAX_MEMBERS,
+        max_members.map(|max_members| max_members.to_string()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::Storage;
+
+    #[test]
+    #[serial_test::serial]
+    fn test_room_metadata() {
+        let mut connection = Storage::memory().get().unwrap();
+        let room_name = format!("metadata-test-{}", models::ServerId::new());
+        assert_eq!(
+            room_metadata(&mut connection, &room_name).unwrap(),
+            models::RoomMetadata {
+                room_name: room_name.clone(),
+                ..Default::default()
+            }
+        );
+
+        assert!(connection.create_room(&room_name, "alice", true).unwrap());
+        assert!(!connection.create_room(&room_name, "bob", false).unwrap());
+        set_topic(&mut connection, &room_name, Some("rust".to_owned())).unwrap();
+        set_description(
+            &mut connection,
+            &room_name,
+            Some("all things rust".to_owned()),
+        )
+        .unwrap();
+        set_max_members(&mut connection, &room_name, Some(10)).unwrap();
+        let metadata = room_metadata(&mut connection, &room_name).unwrap();
+        assert_eq!(metadata.owner.as_deref(), Some("alice"));
+        assert!(metadata.created_at.is_some());
+        assert_eq!(metadata.topic.as_deref(), Some("rust"));
+        assert_eq!(metadata.description.as_deref(), Some("all things rust"));
+        assert_eq!(metadata.max_members, Some(10));
+        assert!(metadata.ephemeral);
+        assert!(!metadata.archived);
+
+        set_topic(&mut connection, &room_name, None).unwrap();
+        set_max_members(&mut connection, &room_name, None).unwrap();
+        let metadata = room_metadata(&mut connection, &room_name).unwrap();
+        assert_eq!(metadata.topic, None);
+        assert_eq!(metadata.max_members, None);
+    }
+}
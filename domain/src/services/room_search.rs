@@ -0,0 +1,227 @@
+use super::room_history;
+use crate::storage::Connection;
+use crate::{models, Result};
+use std::collections::BTreeSet;
+
+/// Terms shorter than this are neither indexed nor searched.
+const MIN_TERM_LENGTH: usize = 2;
+/// Number of characters of a snippet kept before the matching term, twice as many are kept from it.
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Splits the text into lowercase words, without duplicates.
+pub(crate) fn terms(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_TERM_LENGTH)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Chat messages to look for in a room.
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+    /// Words which must all appear in the message, in any case.
+    pub text: String,
+    /// Only messages of this user.
+    pub author: Option<String>,
+    /// Only messages sent at or after this time (milliseconds since the unix epoch).
+    pub since: Option<i64>,
+    /// Only messages sent before this time (milliseconds since the unix epoch).
+    pub until: Option<i64>,
+    pub limit: usize,
+}
+
+/// Returns whether the text starts with the term once lowercased.
+fn starts_with_term(text: &str, term: &str) -> bool {
+    let mut lowercase = text.chars().flat_map(char::to_lowercase);
+    term.chars().all(|c| lowercase.next() == Some(c))
+}
+
+/// Excerpt of the text around the first occurrence of one of the terms.
+fn snippet(text: &str, terms: &BTreeSet<String>) -> String {
+    // Looked up in the text itself: lowercasing changes the length of some characters.
+    let position = text
+        .char_indices()
+        .map(|(index, _)| index)
+        .find(|&index| {
+            terms
+                .iter()
+                .any(|term| starts_with_term(&text[index..], term))
+        })
+        .unwrap_or_default();
+    let start = text[..position]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT - 1)
+        .map(|(index, _)| index)
+        .unwrap_or_default();
+    let end = text[position..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT * 2)
+        .map(|(index, _)| position + index)
+        .unwrap_or(text.len());
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(&text[start..end]);
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Returns the chat messages of the room matching the query, newest first.
+///
+/// Only the messages still kept in the history can be found.
+pub fn search(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    query: &SearchQuery,
+) -> Result<Vec<models::SearchHit>> {
+    let terms = terms(&query.text);
+    let message_ids = connection.find_messages(room_name, &terms)?;
+    let mut hits = Vec::new();
+    for message_id in message_ids.into_iter().rev() {
+        if hits.len() >= query.limit {
+            break;
+        }
+        let message = room_history::messages_after(connection, room_name, message_id - 1, 1)?
+            .into_iter()
+            .find(|message| message.id == message_id);
+        let (username, context) = match message.as_ref().map(|message| &message.msg) {
+            Some(models::ChatMessage::Chat {
+                username, context, ..
+            })
+            | Some(models::ChatMessage::Action {
+                username,
+                action: context,
+                ..
+            }) => (username, context),
+            _ => continue,
+        };
+        let timestamp = message
+            .as_ref()
+            .map(|message| message.timestamp)
+            .unwrap_or_default();
+        if query
+            .author
+            .as_ref()
+            .map_or(false, |author| author != username)
+            || query.since.map_or(false, |since| timestamp < since)
+            || query.until.map_or(false, |until| timestamp >= until)
+        {
+            continue;
+        }
+        hits.push(models::SearchHit {
+            message_id,
+            timestamp,
+            username: username.clone(),
+            snippet: snippet(context, &terms),
+        });
+    }
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::Storage;
+
+    fn chat(username: &str, room_name: &str, context: &str) -> models::ChatMessage {
+        models::ChatMessage::Chat {
+            username: username.to_owned(),
+            room_name: room_name.to_owned(),
+            context: context.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_snippet() {
+        let terms = ["postgres".to_owned()].into_iter().collect();
+        assert_eq!(
+            snippet("We picked Postgres.", &terms),
+            "We picked Postgres."
+        );
+        let long = format!("{} postgres {}", "a".repeat(100), "b".repeat(100));
+        let snippet = snippet(&long, &terms);
+        assert!(snippet.starts_with('…') && snippet.ends_with('…'));
+        assert!(snippet.contains("postgres"));
+        assert_eq!(snippet.chars().count(), SNIPPET_CONTEXT * 3 + 2);
+
+        // 'İ' grows and 'K' (Kelvin sign) shrinks once lowercased.
+        let text = format!(
+            "{}{} Postgres",
+            "\u{130}\u{212A}".repeat(30),
+            "a".repeat(50)
+        );
+        let snippet = super::snippet(&text, &terms);
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with("Postgres"));
+        assert!(super::snippet(
+            "\u{212A}elvin",
+            &["kelvin".to_owned()].into_iter().collect()
+        )
+        .starts_with('\u{212A}'));
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_search() {
+        let mut connection = Storage::memory().get().unwrap();
+        let room_name = format!("search-test-{}", models::ServerId::new());
+        for (username, context) in [
+            ("alice", "Should we use Postgres or MySQL?"),
+            ("bob", "Let's decide about the database tomorrow."),
+            ("alice", "We decided: postgres it is."),
+        ] {
+            room_history::append_message(&mut connection, chat(username, &room_name, context))
+                .unwrap();
+        }
+
+        let query = SearchQuery {
+            text: "POSTGRES".to_owned(),
+            limit: 10,
+            ..Default::default()
+        };
+        let hits = search(&mut connection, &room_name, &query).unwrap();
+        assert_eq!(
+            hits.iter().map(|hit| hit.message_id).collect::<Vec<_>>(),
+            vec![3, 1]
+        );
+        assert_eq!(hits[0].snippet, "We decided: postgres it is.");
+        let query = SearchQuery {
+            text: "postgres mysql".to_owned(),
+            ..query
+        };
+        assert_eq!(
+            search(&mut connection, &room_name, &query).unwrap().len(),
+            1
+        );
+        let query = SearchQuery {
+            text: "the database".to_owned(),
+            author: Some("alice".to_owned()),
+            ..query
+        };
+        assert!(search(&mut connection, &room_name, &query)
+            .unwrap()
+            .is_empty());
+        let query = SearchQuery {
+            author: None,
+            since: Some(hits[0].timestamp + 1),
+            ..query
+        };
+        assert!(search(&mut connection, &room_name, &query)
+            .unwrap()
+            .is_empty());
+
+        connection.delete_room(&room_name).unwrap();
+        let query = SearchQuery {
+            text: "postgres".to_owned(),
+            limit: 10,
+            ..Default::default()
+        };
+        assert!(search(&mut connection, &room_name, &query)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,586 @@
+use crate::storage::{Connection, Storage};
+use crate::{models, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Header carrying `sha256=<hex digest>` of `<timestamp>.<body>` keyed with the subscription secret.
+pub const SIGNATURE_HEADER: &str = "X-Chat-Signature";
+/// Header carrying the milliseconds since the unix epoch at which the delivery was signed.
+pub const TIMESTAMP_HEADER: &str = "X-Chat-Timestamp";
+
+/// Number of dead letters kept for each room.
+pub(crate) const DEAD_LETTERS_LENGTH: isize = 1000;
+
+/// Number of deliveries waiting for a worker; the next ones go straight to the dead letters.
+const DELIVERY_QUEUE_LENGTH: usize = 1000;
+
+/// Whether the address is reachable from the internet, i.e. not loopback, private, link-local,
+/// shared, benchmarking, multicast or otherwise reserved. IPv6 addresses which embed an IPv4 one
+/// (mapped, 6to4 or Teredo) are checked by the latter.
+pub fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(first == 0
+                || first >= 240
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || (first == 100 && second & 0xc0 == 64)
+                || (first == 198 && second & 0xfe == 18))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let ipv4 =
+                |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                is_public_address(IpAddr::V4(ip))
+            } else if segments[0] == 0x2002 {
+                is_public_address(IpAddr::V4(ipv4(segments[1], segments[2])))
+            } else if segments[..2] == [0x2001, 0] {
+                // Teredo stores the address of the client inverted.
+                is_public_address(IpAddr::V4(ipv4(!segments[6], !segments[7])))
+            } else {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || segments[0] & 0xfe00 == 0xfc00
+                    || segments[0] & 0xffc0 == 0xfe80
+                    || segments[..2] == [0x2001, 0xdb8])
+            }
+        }
+    }
+}
+
+/// Checks the URL of a new subscription: `http://` or `https://`, and not to `localhost` or a
+/// private address unless `allow_private` is set.
+///
+/// Host names are checked again when the deliveries resolve them.
+pub fn check_url(url: &str, allow_private: bool) -> core::result::Result<(), &'static str> {
+    let url = url::Url::parse(url).map_err(|_| "Unsupported webhook URL.")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("Unsupported webhook URL.");
+    }
+    if allow_private {
+        return Ok(());
+    }
+    let public = match url.host() {
+        Some(url::Host::Ipv4(ip)) => is_public_address(ip.into()),
+        Some(url::Host::Ipv6(ip)) => is_public_address(ip.into()),
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.to_ascii_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+        None => false,
+    };
+    if public {
+        Ok(())
+    } else {
+        Err("Webhooks can't be delivered to private addresses.")
+    }
+}
+
+/// Resolves the host of a delivery to its public addresses only, so that no host name leads a
+/// delivery into the private network.
+fn resolve_public(netloc: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = netloc
+        .to_socket_addrs()?
+        .filter(|addr| is_public_address(addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} has no public address", netloc),
+        ));
+    }
+    Ok(addrs)
+}
+
+pub fn add_subscription(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    url: &str,
+    events: Vec<models::WebhookEvent>,
+) -> Result<models::WebhookSubscription> {
+    let subscription = models::WebhookSubscription {
+        id: uuid::Uuid::new_v4().to_string(),
+        room_name: room_name.to_owned(),
+        url: url.to_owned(),
+        secret: uuid::Uuid::new_v4().simple().to_string(),
+        events,
+    };
+    connection.add_webhook(&subscription)?;
+    Ok(subscription)
+}
+
+pub fn subscriptions(
+    connection: &mut dyn Connection,
+    room_name: &str,
+) -> Result<Vec<models::WebhookSubscription>> {
+    connection.webhooks(room_name)
+}
+
+/// Returns whether the subscription existed.
+pub fn remove_subscription(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    subscription_id: &str,
+) -> Result<bool> {
+    connection.remove_webhook(room_name, subscription_id)
+}
+
+/// Returns the latest dead letters of the room, oldest first.
+pub fn dead_letters(
+    connection: &mut dyn Connection,
+    room_name: &str,
+) -> Result<Vec<models::DeadLetter>> {
+    connection.dead_letters(room_name)
+}
+
+fn push_dead_letter(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    dead_letter: &models::DeadLetter,
+) -> Result<()> {
+    connection.push_dead_letter(room_name, dead_letter)
+}
+
+/// Records a delivery which won't be attempted anymore.
+fn store_dead_letter(storage: &Storage, delivery: Delivery, attempts: u32, error: String) {
+    let subscription = delivery.subscription;
+    tracing::warn!(
+        "webhook {} of {} failed after {} attempts: {}",
+        subscription.id,
+        subscription.room_name,
+        attempts,
+        error
+    );
+    let dead_letter = models::DeadLetter {
+        subscription_id: subscription.id.clone(),
+        url: subscription.url.clone(),
+        payload: delivery.body,
+        attempts,
+        error,
+        failed_at: models::unix_millis(),
+    };
+    let result = storage
+        .get()
+        .and_then(|mut conn| push_dead_letter(&mut conn, &subscription.room_name, &dead_letter));
+    if let Err(e) = result {
+        tracing::error!("failed to store dead letter: {}", e);
+    }
+}
+
+/// Returns the value of the `SIGNATURE_HEADER` for a delivery.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Timeout of a single delivery attempt.
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the attempt following the given (1-based) failed attempt.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// Posts the body to the subscription, retrying failures with an exponential backoff.
+///
+/// Client errors other than `408` and `429` are not retried. Returns the number of attempts and
+/// the last error when the delivery didn't succeed.
+pub fn deliver(
+    agent: &ureq::Agent,
+    subscription: &models::WebhookSubscription,
+    body: &str,
+    policy: &RetryPolicy,
+) -> core::result::Result<(), (u32, String)> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let timestamp = models::unix_millis();
+        let result = agent
+            .post(&subscription.url)
+            .set("Content-Type", "application/json")
+            .set(TIMESTAMP_HEADER, &timestamp.to_string())
+            .set(
+                SIGNATURE_HEADER,
+                &sign(&subscription.secret, timestamp, body),
+            )
+            .send_string(body);
+        let (retryable, error) = match result {
+            Ok(_) => return Ok(()),
+            Err(ureq::Error::Status(status, _)) => (
+                status >= 500 || status == 408 || status == 429,
+                format!("status {}", status),
+            ),
+            Err(e) => (true, e.to_string()),
+        };
+        if !retryable || attempt >= policy.max_attempts {
+            return Err((attempt, error));
+        }
+        thread::sleep(policy.backoff(attempt));
+    }
+}
+
+struct Delivery {
+    subscription: models::WebhookSubscription,
+    body: String,
+}
+
+/// Delivers the events of the rooms to their webhook subscriptions.
+///
+/// It consumes the same channel as `ChatRoomSubscriberService` but only handles the messages
+/// published by its own server, so that each message is delivered once across all servers.
+pub struct WebhookDispatcherService {
+    storage: Storage,
+    server_id: models::ServerId,
+    channel_name: String,
+    retry_policy: RetryPolicy,
+    workers: usize,
+    allow_private_destinations: bool,
+}
+
+impl WebhookDispatcherService {
+    /// Deliveries to private addresses (see `is_public_address`) fail unless
+    /// `allow_private_destinations` is set.
+    pub fn new<S: Into<models::ServerId>>(
+        storage: Storage,
+        server_id: S,
+        channel_name: String,
+        retry_policy: RetryPolicy,
+        workers: usize,
+        allow_private_destinations: bool,
+    ) -> Self {
+        Self {
+            storage,
+            server_id: server_id.into(),
+            channel_name,
+            retry_policy,
+            workers: workers.max(1),
+            allow_private_destinations,
+        }
+    }
+
+    fn spawn_workers(&self) -> mpsc::SyncSender<Delivery> {
+        let (sender, receiver) = mpsc::sync_channel::<Delivery>(DELIVERY_QUEUE_LENGTH);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut agent = ureq::AgentBuilder::new().timeout(self.retry_policy.timeout);
+        if !self.allow_private_destinations {
+            agent = agent.resolver(resolve_public);
+        }
+        let agent = agent.build();
+        for _ in 0..self.workers {
+            let receiver = receiver.clone();
+            let agent = agent.clone();
+            let storage = self.storage.clone();
+            let policy = self.retry_policy.clone();
+            thread::spawn(move || loop {
+                let delivery = match receiver.lock().unwrap().recv() {
+                    Ok(delivery) => delivery,
+                    Err(_) => break,
+                };
+                if let Err((attempts, error)) =
+                    deliver(&agent, &delivery.subscription, &delivery.body, &policy)
+                {
+                    store_dead_letter(&storage, delivery, attempts, error);
+                }
+            });
+        }
+        sender
+    }
+
+    /// Hands the events of this server to the workers, subscribing again after a failure.
+    pub fn start(self) {
+        let deliveries = self.spawn_workers();
+        super::channel::consume(
+            &self.storage,
+            &self.channel_name,
+            || {},
+            |payload| self.dispatch(&payload, &deliveries),
+        );
+    }
+
+    fn dispatch(&self, payload: &[u8], deliveries: &mpsc::SyncSender<Delivery>) {
+        let message = match models::decode_payload(payload) {
+            Ok(message) if message.id == self.server_id => message,
+            _ => return,
+        };
+        let msg = match message.msg {
+            models::ChatMessagePayload::Known(msg) => msg,
+            models::ChatMessagePayload::Unknown(_) => return,
+        };
+        let event = models::WebhookEvent::of(&msg);
+        let subscriptions = match self
+            .storage
+            .get()
+            .and_then(|mut conn| subscriptions(&mut conn, msg.room_name()))
+        {
+            Ok(subscriptions) => subscriptions,
+            Err(e) => {
+                tracing::error!("failed to load webhooks of {}: {}", msg.room_name(), e);
+                return;
+            }
+        };
+        if subscriptions.is_empty() {
+            return;
+        }
+        let payload = models::WebhookPayload {
+            event,
+            message: models::RoomMessage {
+                id: message.message_id,
+                timestamp: message.timestamp,
+                msg,
+            },
+        };
+        let body = serde_json::to_string(&payload).unwrap();
+        for subscription in subscriptions {
+            if !subscription.accepts(event) {
+                continue;
+            }
+            let delivery = Delivery {
+                subscription,
+                body: body.clone(),
+            };
+            // Waiting for the workers would hold up the channel of every room.
+            if let Err(mpsc::TrySendError::Full(delivery)) = deliveries.try_send(delivery) {
+                store_dead_letter(&self.storage, delivery, 0, "delivery queue full".to_owned());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Answers each request with the next status and hands the request headers and body back.
+    fn serve(statuses: Vec<u16>) -> (String, mpsc::Receiver<(Vec<String>, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut headers = vec![];
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_owned();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                    headers.push(line);
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                sender
+                    .send((headers, String::from_utf8(body).unwrap()))
+                    .unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn subscription(url: String) -> models::WebhookSubscription {
+        models::WebhookSubscription {
+            id: "hook".to_owned(),
+            room_name: "lobby".to_owned(),
+            url,
+            secret: "secret".to_owned(),
+            events: vec![],
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn header<'a>(headers: &'a [String], name: &str) -> &'a str {
+        headers
+            .iter()
+            .find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(10), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_deliver_signs_payload() {
+        let (url, requests) = serve(vec![200]);
+        deliver(
+            &ureq::agent(),
+            &subscription(url),
+            r#"{"event":"chat"}"#,
+            &policy(),
+        )
+        .unwrap();
+        let (headers, body) = requests.recv().unwrap();
+        assert_eq!(body, r#"{"event":"chat"}"#);
+        let timestamp: i64 = header(&headers, TIMESTAMP_HEADER).parse().unwrap();
+        assert_eq!(
+            header(&headers, SIGNATURE_HEADER),
+            sign("secret", timestamp, &body)
+        );
+    }
+
+    #[test]
+    fn test_deliver_retries_server_errors() {
+        let (url, requests) = serve(vec![500, 503, 200]);
+        deliver(&ureq::agent(), &subscription(url), "{}", &policy()).unwrap();
+        assert_eq!(requests.iter().count(), 3);
+    }
+
+    #[test]
+    fn test_deliver_gives_up() {
+        let (url, requests) = serve(vec![500, 500, 500]);
+        let result = deliver(&ureq::agent(), &subscription(url), "{}", &policy());
+        assert_eq!(result, Err((3, "status 500".to_owned())));
+        assert_eq!(requests.iter().count(), 3);
+
+        let (url, requests) = serve(vec![404]);
+        let result = deliver(&ureq::agent(), &subscription(url), "{}", &policy());
+        assert_eq!(result, Err((1, "status 404".to_owned())));
+        assert_eq!(requests.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_private_destinations() {
+        assert!(check_url("https://example.com/hook", false).is_ok());
+        assert!(check_url("http://93.184.216.34/hook", false).is_ok());
+        assert!(check_url("http://198.20.0.1/hook", false).is_ok());
+        assert!(check_url("http://[2002:5db8:d822::1]/hook", false).is_ok());
+        assert!(check_url("http://[2001:0:4136:e378:8000:63bf:a247:27dd]/hook", false).is_ok());
+        for url in [
+            "http://localhost/hook",
+            "http://api.localhost/hook",
+            "http://127.0.0.1:8080/hook",
+            "http://10.1.2.3/hook",
+            "http://169.254.169.254/latest",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:192.168.0.1]/hook",
+            "http://198.18.0.1/hook",
+            "http://198.19.255.255/hook",
+            "http://240.0.0.1/hook",
+            "http://255.255.255.255/hook",
+            "http://[2001:db8::1]/hook",
+            "http://[2002:c0a8:1::1]/hook",
+            "http://[2002:7f00:1::1]/hook",
+            "http://[2001:0:4136:e378:8000:63bf:f5ff:fffe]/hook",
+        ] {
+            assert!(check_url(url, false).is_err(), "{}", url);
+            assert!(check_url(url, true).is_ok(), "{}", url);
+        }
+        assert!(check_url("ftp://example.com/hook", true).is_err());
+        assert!(check_url("not a url", true).is_err());
+
+        assert!(resolve_public("127.0.0.1:80").is_err());
+        let (url, _) = serve(vec![]);
+        let agent = ureq::AgentBuilder::new().resolver(resolve_public).build();
+        let result = deliver(&agent, &subscription(url), "{}", &policy());
+        assert_eq!(result.map_err(|(attempts, _)| attempts), Err(3));
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_subscriptions_and_dead_letters() {
+        let mut connection = Storage::memory().get().unwrap();
+        let room_name = format!("webhook-test-{}", models::ServerId::new());
+        let subscription = add_subscription(
+            &mut connection,
+            &room_name,
+            "http://localhost/hook",
+            vec![models::WebhookEvent::Chat],
+        )
+        .unwrap();
+        assert!(subscription.accepts(models::WebhookEvent::Chat));
+        assert!(!subscription.accepts(models::WebhookEvent::Join));
+        assert_eq!(
+            subscriptions(&mut connection, &room_name).unwrap(),
+            vec![subscription.clone()]
+        );
+
+        let dead_letter = models::DeadLetter {
+            subscription_id: subscription.id.clone(),
+            url: subscription.url.clone(),
+            payload: "{}".to_owned(),
+            attempts: 5,
+            error: "status 500".to_owned(),
+            failed_at: models::unix_millis(),
+        };
+        push_dead_letter(&mut connection, &room_name, &dead_letter).unwrap();
+        assert_eq!(
+            dead_letters(&mut connection, &room_name).unwrap(),
+            vec![dead_letter]
+        );
+
+        assert!(remove_subscription(&mut connection, &room_name, &subscription.id).unwrap());
+        assert!(!remove_subscription(&mut connection, &room_name, &subscription.id).unwrap());
+        assert!(subscriptions(&mut connection, &room_name)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,651 @@
+//! Where the domain keeps its state and exchanges messages between servers.
+//!
+//! Services and endpoints talk to a `Connection`, which offers the operations on rooms the domain
+//! relies on. `DragonflyBackend` runs them against dragonfly, so that several servers share the
+//! rooms, while `MemoryBackend` keeps everything in the process for a single server (and for
+//! tests).
+
+mod dragonfly_backend;
+mod memory_backend;
+
+pub use dragonfly_backend::*;
+pub use memory_backend::*;
+
+use crate::{keys::Keys, models, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+/// Outcome of `Connection::join_room`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinOutcome {
+    /// The user wasn't in the room: the session reserved the username.
+    FirstSession,
+    /// The user was in the room and the session presented the same identity digest.
+    OtherSession,
+    /// Another user holds the username (or the identity digest doesn't match).
+    UsernameTaken,
+    /// The room already has the maximum number of users.
+    RoomFull,
+}
+
+/// Outcome of `Connection::rename_member`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameOutcome {
+    Renamed,
+    UsernameTaken,
+    /// The user holds other sessions in the room, which would keep the current username.
+    OtherSessions,
+}
+
+/// Operations on the rooms, their members, history, read receipts and webhooks, and PUB/SUB
+/// channels between servers.
+///
+/// Every operation which changes the storage is atomic. Operations on a room which doesn't exist
+/// behave as on an empty room.
+pub trait Connection: Send {
+    /// Returns the names of all existing rooms, sorted.
+    fn room_names(&mut self) -> Result<Vec<String>>;
+    /// Creates the room unless it exists, and lists it in the directory either way (which also
+    /// lists the rooms created before the directory existed). Returns whether it was created.
+    fn create_room(&mut self, room_name: &str, owner: &str, ephemeral: bool) -> Result<bool>;
+    /// Deletes the room with its members, metadata, invites, history, read receipts and webhooks.
+    ///
+    /// The sequence of message ids is kept: a room created again under the same name continues
+    /// it, so that cursors of clients never point to messages of the previous room.
+    fn delete_room(&mut self, room_name: &str) -> Result<()>;
+    /// Deletes the room like `delete_room` unless users are in it, at once. Returns whether it was
+    /// deleted.
+    fn delete_empty_room(&mut self, room_name: &str) -> Result<bool>;
+    /// Deletes the room like `delete_room` if it is an empty ephemeral room which isn't archived, at
+    /// once. Returns whether it was deleted.
+    fn cleanup_room(&mut self, room_name: &str) -> Result<bool>;
+    /// Returns the metadata fields of the room (see `services::room_metadata`).
+    fn room_metadata(&mut self, room_name: &str) -> Result<HashMap<String, String>>;
+    /// Sets the metadata field, or removes it with `None`.
+    fn set_room_metadata(
+        &mut self,
+        room_name: &str,
+        field: &str,
+        value: Option<&str>,
+    ) -> Result<()>;
+
+    /// Returns the usernames in the room, sorted.
+    fn members(&mut self, room_name: &str) -> Result<Vec<String>>;
+    fn member_count(&mut self, room_name: &str) -> Result<usize>;
+    /// Adds a session of the user to the room.
+    ///
+    /// The first session of a user reserves the username under `identity_digest` unless the room
+    /// already has `max_members` users; the other sessions must present the same digest.
+    fn join_room(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        identity_digest: &str,
+        max_members: Option<usize>,
+    ) -> Result<JoinOutcome>;
+    /// Removes a session of the user from the room. Returns whether it was the last session of the
+    /// user, whose username is released; `false` when the user isn't in the room (e.g. the room was
+    /// deleted meanwhile).
+    fn leave_room(&mut self, room_name: &str, username: &str) -> Result<bool>;
+    /// Moves the reservation and the read receipt of the user to another username, unless the user
+    /// holds several sessions in the room.
+    fn rename_member(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        new_username: &str,
+    ) -> Result<RenameOutcome>;
+
+    /// Invites the user, who proves it with the token of the digest (replacing any previous one).
+    fn invite(&mut self, room_name: &str, username: &str, token_digest: &str) -> Result<()>;
+    /// Returns the invited usernames, sorted.
+    fn invites(&mut self, room_name: &str) -> Result<Vec<String>>;
+    fn is_invited(&mut self, room_name: &str, username: &str, token_digest: &str) -> Result<bool>;
+
+    /// Assigns the next id of the room to the message and appends it to the history of the room,
+    /// indexed under the search terms. Only the latest `room_history::HISTORY_LENGTH` messages
+    /// are kept.
+    fn append_message(
+        &mut self,
+        msg: &models::ChatMessage,
+        search_terms: &BTreeSet<String>,
+    ) -> Result<models::RoomMessage>;
+    /// Returns up to `limit` messages of the room with an id greater than `after`, oldest first.
+    fn messages_after(
+        &mut self,
+        room_name: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<models::RoomMessage>>;
+    /// Returns the id of the latest message of the room, `0` if nothing was ever sent to it.
+    fn last_message_id(&mut self, room_name: &str) -> Result<u64>;
+    /// Returns the ids of the messages kept in the history indexed under all the search terms.
+    fn find_messages(
+        &mut self,
+        room_name: &str,
+        search_terms: &BTreeSet<String>,
+    ) -> Result<BTreeSet<u64>>;
+
+    /// Returns the id of the last message the user read in the room, if the user ever read it.
+    fn last_read(&mut self, room_name: &str, username: &str) -> Result<Option<u64>>;
+    /// Moves the read receipt of the user forward to the message, or to the latest message if the
+    /// id is greater. Returns the new read position, or `None` if the user had read that far.
+    fn mark_read(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        message_id: u64,
+    ) -> Result<Option<u64>>;
+    /// Returns the rooms in which the user has a read receipt, sorted. Rooms deleted since may be
+    /// listed.
+    fn read_rooms(&mut self, username: &str) -> Result<Vec<String>>;
+
+    fn add_webhook(&mut self, subscription: &models::WebhookSubscription) -> Result<()>;
+    fn webhooks(&mut self, room_name: &str) -> Result<Vec<models::WebhookSubscription>>;
+    /// Returns whether the subscription existed.
+    fn remove_webhook(&mut self, room_name: &str, subscription_id: &str) -> Result<bool>;
+    /// Records a failed delivery; only the latest `webhook::DEAD_LETTERS_LENGTH` are kept.
+    fn push_dead_letter(&mut self, room_name: &str, dead_letter: &models::DeadLetter)
+        -> Result<()>;
+    /// Returns the dead letters of the room, oldest first.
+    fn dead_letters(&mut self, room_name: &str) -> Result<Vec<models::DeadLetter>>;
+
+    fn publish(&mut self, channel_name: &str, payload: &[u8]) -> Result<()>;
+    /// Subscribes the connection to the channel; it can't run other operations meanwhile.
+    fn subscribe<'a>(&'a mut self, channel_name: &str) -> Result<Box<dyn Subscription + 'a>>;
+}
+
+/// Lets the connections handed out by `Storage::get` be passed where a `&mut dyn Connection` is
+/// expected.
+impl<C: Connection + ?Sized> Connection for Box<C> {
+    fn room_names(&mut self) -> Result<Vec<String>> {
+        (**self).room_names()
+    }
+
+    fn create_room(&mut self, room_name: &str, owner: &str, ephemeral: bool) -> Result<bool> {
+        (**self).create_room(room_name, owner, ephemeral)
+    }
+
+    fn delete_room(&mut self, room_name: &str) -> Result<()> {
+        (**self).delete_room(room_name)
+    }
+
+    fn delete_empty_room(&mut self, room_name: &str) -> Result<bool> {
+        (**self).delete_empty_room(room_name)
+    }
+
+    fn cleanup_room(&mut self, room_name: &str) -> Result<bool> {
+        (**self).cleanup_room(room_name)
+    }
+
+    fn room_metadata(&mut self, room_name: &str) -> Result<HashMap<String, String>> {
+        (**self).room_metadata(room_name)
+    }
+
+    fn set_room_metadata(
+        &mut self,
+        room_name: &str,
+        field: &str,
+        value: Option<&str>,
+    ) -> Result<()> {
+        (**self).set_room_metadata(room_name, field, value)
+    }
+
+    fn members(&mut self, room_name: &str) -> Result<Vec<String>> {
+        (**self).members(room_name)
+    }
+
+    fn member_count(&mut self, room_name: &str) -> Result<usize> {
+        (**self).member_count(room_name)
+    }
+
+    fn join_room(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        identity_digest: &str,
+        max_members: Option<usize>,
+    ) -> Result<JoinOutcome> {
+        (**self).join_room(room_name, username, identity_digest, max_members)
+    }
+
+    fn leave_room(&mut self, room_name: &str, username: &str) -> Result<bool> {
+        (**self).leave_room(room_name, username)
+    }
+
+    fn rename_member(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        new_username: &str,
+    ) -> Result<RenameOutcome> {
+        (**self).rename_member(room_name, username, new_username)
+    }
+
+    fn invite(&mut self, room_name: &str, username: &str, token_digest: &str) -> Result<()> {
+        (**self).invite(room_name, username, token_digest)
+    }
+
+    fn invites(&mut self, room_name: &str) -> Result<Vec<String>> {
+        (**self).invites(room_name)
+    }
+
+    fn is_invited(&mut self, room_name: &str, username: &str, token_digest: &str) -> Result<bool> {
+        (**self).is_invited(room_name, username, token_digest)
+    }
+
+    fn append_message(
+        &mut self,
+        msg: &models::ChatMessage,
+        search_terms: &BTreeSet<String>,
+    ) -> Result<models::RoomMessage> {
+        (**self).append_message(msg, search_terms)
+    }
+
+    fn messages_after(
+        &mut self,
+        room_name: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<models::RoomMessage>> {
+        (**self).messages_after(room_name, after, limit)
+    }
+
+    fn last_message_id(&mut self, room_name: &str) -> Result<u64> {
+        (**self).last_message_id(room_name)
+    }
+
+    fn find_messages(
+        &mut self,
+        room_name: &str,
+        search_terms: &BTreeSet<String>,
+    ) -> Result<BTreeSet<u64>> {
+        (**self).find_messages(room_name, search_terms)
+    }
+
+    fn last_read(&mut self, room_name: &str, username: &str) -> Result<Option<u64>> {
+        (**self).last_read(room_name, username)
+    }
+
+    fn mark_read(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        message_id: u64,
+    ) -> Result<Option<u64>> {
+        (**self).mark_read(room_name, username, message_id)
+    }
+
+    fn read_rooms(&mut self, username: &str) -> Result<Vec<String>> {
+        (**self).read_rooms(username)
+    }
+
+    fn add_webhook(&mut self, subscription: &models::WebhookSubscription) -> Result<()> {
+        (**self).add_webhook(subscription)
+    }
+
+    fn webhooks(&mut self, room_name: &str) -> Result<Vec<models::WebhookSubscription>> {
+        (**self).webhooks(room_name)
+    }
+
+    fn remove_webhook(&mut self, room_name: &str, subscription_id: &str) -> Result<bool> {
+        (**self).remove_webhook(room_name, subscription_id)
+    }
+
+    fn push_dead_letter(
+        &mut self,
+        room_name: &str,
+        dead_letter: &models::DeadLetter,
+    ) -> Result<()> {
+        (**self).push_dead_letter(room_name, dead_letter)
+    }
+
+    fn dead_letters(&mut self, room_name: &str) -> Result<Vec<models::DeadLetter>> {
+        (**self).dead_letters(room_name)
+    }
+
+    fn publish(&mut self, channel_name: &str, payload: &[u8]) -> Result<()> {
+        (**self).publish(channel_name, payload)
+    }
+
+    fn subscribe<'a>(&'a mut self, channel_name: &str) -> Result<Box<dyn Subscription + 'a>> {
+        (**self).subscribe(channel_name)
+    }
+}
+
+/// Payloads published to a channel after subscribing to it.
+pub trait Subscription {
+    /// Waits for the next payload; fails once the subscription is broken.
+    fn next_payload(&mut self) -> Result<Vec<u8>>;
+}
+
+/// Source of connections.
+pub trait Backend: Send + Sync {
+    /// Connection naming its keys and channels with `keys`, for backends which need names.
+    fn connection(&self, keys: &Keys) -> Result<Box<dyn Connection>>;
+
+    /// Connection for reads tolerating a slight delay, which may go to a replica.
+    fn replica_connection(&self, keys: &Keys) -> Result<Box<dyn Connection>> {
+        self.connection(keys)
+    }
+}
+
+/// Shared handle on a `Backend`, within a namespace of keys.
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn Backend>,
+    keys: Keys,
+}
+
+impl Storage {
+    pub fn new<B: Backend + 'static>(backend: B) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            keys: Keys::default(),
+        }
+    }
+
+    /// Moves the storage to another namespace (see `keys`), so that deployments can share a
+    /// database.
+    pub fn with_namespace<S: Into<String>>(self, namespace: S) -> Self {
+        Self {
+            keys: Keys::new(namespace),
+            ..self
+        }
+    }
+
+    /// Storage shared by the servers using the same dragonfly database.
+    pub fn dragonfly(redis_pool: dragonfly::RedisPool) -> Self {
+        Self::new(DragonflyBackend::new(redis_pool))
+    }
+
+    /// Dragonfly storage serving `get_replica` from the replicas of `replica_pool`.
+    pub fn dragonfly_with_replicas(
+        redis_pool: dragonfly::RedisPool,
+        replica_pool: dragonfly::RedisPool,
+    ) -> Self {
+        Self::new(DragonflyBackend::with_replicas(redis_pool, replica_pool))
+    }
+
+    /// Storage of this process only.
+    pub fn memory() -> Self {
+        Self::new(MemoryBackend::default())
+    }
+
+    pub fn get(&self) -> Result<Box<dyn Connection>> {
+        self.backend.connection(&self.keys)
+    }
+
+    /// Returns a connection for reads only, which may lag behind the writes (see
+    /// `Backend::replica_connection`).
+    pub fn get_replica(&self) -> Result<Box<dyn Connection>> {
+        self.backend.replica_connection(&self.keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chat(room_name: &str, context: &str) -> models::ChatMessage {
+        models::ChatMessage::Chat {
+            username: "alice".to_owned(),
+            room_name: room_name.to_owned(),
+            context: context.to_owned(),
+        }
+    }
+
+    fn search_terms(terms: &[&str]) -> BTreeSet<String> {
+        terms.iter().map(|term| term.to_string()).collect()
+    }
+
+    /// Checks the behaviors of a backend the domain relies on.
+    fn check_connection(connection: &mut dyn Connection, prefix: &str) {
+        let room = format!("{}-room", prefix);
+        let room = room.as_str();
+
+        assert!(connection.create_room(room, "alice", true).unwrap());
+        assert!(!connection.create_room(room, "bob", false).unwrap());
+        assert!(connection.room_names().unwrap().contains(&room.to_owned()));
+        let metadata = connection.room_metadata(room).unwrap();
+        assert_eq!(metadata.get("owner").map(String::as_str), Some("alice"));
+        assert!(metadata.contains_key("ephemeral"));
+        connection
+            .set_room_metadata(room, "topic", Some("rust"))
+            .unwrap();
+        connection.set_room_metadata(room, "owner", None).unwrap();
+        let metadata = connection.room_metadata(room).unwrap();
+        assert_eq!(metadata.get("topic").map(String::as_str), Some("rust"));
+        assert!(!metadata.contains_key("owner"));
+
+        let join = |connection: &mut dyn Connection, username, digest| {
+            connection
+                .join_room(room, username, digest, Some(2))
+                .unwrap()
+        };
+        assert_eq!(join(connection, "alice", "a"), JoinOutcome::FirstSession);
+        assert_eq!(join(connection, "alice", "b"), JoinOutcome::UsernameTaken);
+        assert_eq!(join(connection, "alice", "a"), JoinOutcome::OtherSession);
+        assert_eq!(join(connection, "bob", "b"), JoinOutcome::FirstSession);
+        assert_eq!(join(connection, "carol", "c"), JoinOutcome::RoomFull);
+        assert_eq!(connection.members(room).unwrap(), vec!["alice", "bob"]);
+        assert_eq!(
+            connection.rename_member(room, "alice", "carol").unwrap(),
+            RenameOutcome::OtherSessions
+        );
+        assert!(!connection.leave_room(room, "alice").unwrap());
+        assert_eq!(
+            connection.rename_member(room, "alice", "bob").unwrap(),
+            RenameOutcome::UsernameTaken
+        );
+        assert_eq!(
+            connection.rename_member(room, "alice", "carol").unwrap(),
+            RenameOutcome::Renamed
+        );
+        assert_eq!(connection.members(room).unwrap(), vec!["bob", "carol"]);
+        assert_eq!(join(connection, "carol", "a"), JoinOutcome::OtherSession);
+        assert!(!connection.leave_room(room, "carol").unwrap());
+        assert!(connection.leave_room(room, "carol").unwrap());
+        assert_eq!(connection.member_count(room).unwrap(), 1);
+        assert!(!connection.cleanup_room(room).unwrap());
+
+        assert!(!connection.is_invited(room, "dave", "d").unwrap());
+        connection.invite(room, "dave", "d").unwrap();
+        connection.invite(room, "carol", "c").unwrap();
+        assert!(connection.is_invited(room, "dave", "d").unwrap());
+        assert!(!connection.is_invited(room, "dave", "c").unwrap());
+        assert_eq!(connection.invites(room).unwrap(), vec!["carol", "dave"]);
+
+        assert_eq!(connection.last_message_id(room).unwrap(), 0);
+        let first = connection
+            .append_message(&chat(room, "Hello Rust"), &search_terms(&["hello", "rust"]))
+            .unwrap();
+        let second = connection
+            .append_message(&chat(room, "Rust!"), &search_terms(&["rust"]))
+            .unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+        assert_eq!(connection.last_message_id(room).unwrap(), 2);
+        assert_eq!(
+            connection.messages_after(room, 0, 10).unwrap(),
+            vec![first.clone(), second.clone()]
+        );
+        assert_eq!(
+            connection.messages_after(room, 1, 10).unwrap(),
+            vec![second]
+        );
+        assert_eq!(connection.messages_after(room, 0, 1).unwrap(), vec![first]);
+        assert_eq!(
+            connection
+                .find_messages(room, &search_terms(&["rust"]))
+                .unwrap(),
+            [1, 2].into_iter().collect()
+        );
+        assert_eq!(
+            connection
+                .find_messages(room, &search_terms(&["rust", "hello"]))
+                .unwrap(),
+            [1].into_iter().collect()
+        );
+
+        assert_eq!(connection.last_read(room, "bob").unwrap(), None);
+        assert_eq!(connection.mark_read(room, "bob", 1).unwrap(), Some(1));
+        assert_eq!(connection.mark_read(room, "bob", 1).unwrap(), None);
+        assert_eq!(connection.mark_read(room, "bob", 10).unwrap(), Some(2));
+        assert_eq!(connection.last_read(room, "bob").unwrap(), Some(2));
+        assert!(connection
+            .read_rooms("bob")
+            .unwrap()
+            .contains(&room.to_owned()));
+        assert_eq!(
+            connection.rename_member(room, "bob", "dave").unwrap(),
+            RenameOutcome::Renamed
+        );
+        assert_eq!(connection.last_read(room, "bob").unwrap(), None);
+        assert_eq!(connection.last_read(room, "dave").unwrap(), Some(2));
+        assert!(!connection
+            .read_rooms("bob")
+            .unwrap()
+            .contains(&room.to_owned()));
+        assert!(connection
+            .read_rooms("dave")
+            .unwrap()
+            .contains(&room.to_owned()));
+        assert_eq!(
+            connection.rename_member(room, "dave", "bob").unwrap(),
+            RenameOutcome::Renamed
+        );
+
+        let subscription = models::WebhookSubscription {
+            id: "hook".to_owned(),
+            room_name: room.to_owned(),
+            url: "http://localhost/hook".to_owned(),
+            secret: "secret".to_owned(),
+            events: vec![],
+        };
+        connection.add_webhook(&subscription).unwrap();
+        assert_eq!(connection.webhooks(room).unwrap(), vec![subscription]);
+        let dead_letter = |attempts| models::DeadLetter {
+            subscription_id: "hook".to_owned(),
+            url: "http://localhost/hook".to_owned(),
+            payload: "{}".to_owned(),
+            attempts,
+            error: "status 500".to_owned(),
+            failed_at: 0,
+        };
+        connection.push_dead_letter(room, &dead_letter(1)).unwrap();
+        connection.push_dead_letter(room, &dead_letter(2)).unwrap();
+        assert_eq!(
+            connection.dead_letters(room).unwrap(),
+            vec![dead_letter(1), dead_letter(2)]
+        );
+        assert!(connection.remove_webhook(room, "hook").unwrap());
+        assert!(!connection.remove_webhook(room, "hook").unwrap());
+
+        assert!(connection.leave_room(room, "bob").unwrap());
+        assert!(connection.cleanup_room(room).unwrap());
+        assert!(!connection.room_names().unwrap().contains(&room.to_owned()));
+        assert!(connection.room_metadata(room).unwrap().is_empty());
+        assert!(connection.invites(room).unwrap().is_empty());
+        assert!(connection.messages_after(room, 0, 10).unwrap().is_empty());
+        assert_eq!(connection.last_message_id(room).unwrap(), 2);
+        assert!(connection
+            .find_messages(room, &search_terms(&["rust"]))
+            .unwrap()
+            .is_empty());
+        assert_eq!(connection.last_read(room, "bob").unwrap(), None);
+        assert!(connection.dead_letters(room).unwrap().is_empty());
+
+        // The sessions of a deleted room end without leaving it.
+        let deleted = format!("{}-deleted-room", prefix);
+        assert_eq!(
+            connection.join_room(&deleted, "alice", "a", None).unwrap(),
+            JoinOutcome::FirstSession
+        );
+        assert!(!connection.delete_empty_room(&deleted).unwrap());
+        assert_eq!(connection.members(&deleted).unwrap(), ["alice"]);
+        connection.delete_room(&deleted).unwrap();
+        assert!(!connection.leave_room(&deleted, "alice").unwrap());
+        assert!(!connection.leave_room(&deleted, "bob").unwrap());
+        assert_eq!(connection.last_message_id(&deleted).unwrap(), 0);
+        assert!(connection.create_room(&deleted, "alice", false).unwrap());
+        assert!(connection.delete_empty_room(&deleted).unwrap());
+        assert!(!connection.room_names().unwrap().contains(&deleted));
+    }
+
+    /// Checks that the messages dropping out of the history can't be found anymore.
+    fn check_history_length(connection: &mut dyn Connection, prefix: &str) {
+        let room = format!("{}-long-room", prefix);
+        let room = room.as_str();
+        let history_length = crate::services::room_history::HISTORY_LENGTH as u64;
+        for id in 1..=history_length + 2 {
+            let terms = if id % 2 == 0 { ["even"] } else { ["odd"] };
+            connection
+                .append_message(&chat(room, "filler"), &search_terms(&terms))
+                .unwrap();
+        }
+        let history = connection.messages_after(room, 0, usize::MAX).unwrap();
+        assert_eq!(history.len() as u64, history_length);
+        assert_eq!(history[0].id, 3);
+        let odd = connection
+            .find_messages(room, &search_terms(&["odd"]))
+            .unwrap();
+        assert_eq!(odd.len() as u64, history_length / 2);
+        assert_eq!(odd.iter().next(), Some(&3));
+        connection.delete_room(room).unwrap();
+    }
+
+    fn check_pub_sub(storage: &Storage, channel: &str) {
+        let (subscribed, wait_subscribed) = std::sync::mpsc::channel();
+        let subscriber_storage = storage.clone();
+        let channel_name = channel.to_owned();
+        let handle = std::thread::spawn(move || {
+            let mut connection = subscriber_storage.get().unwrap();
+            let mut subscription = connection.subscribe(&channel_name).unwrap();
+            subscribed.send(()).unwrap();
+            let mut payloads = Vec::new();
+            while payloads.len() < 2 {
+                payloads.push(subscription.next_payload().unwrap());
+            }
+            payloads
+        });
+        wait_subscribed.recv().unwrap();
+        let mut connection = storage.get().unwrap();
+        connection.publish(channel, b"first").unwrap();
+        connection.publish(channel, &[0, 1, 2]).unwrap();
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![b"first".to_vec(), vec![0, 1, 2]]
+        );
+    }
+
+    #[test]
+    fn test_memory_backend() {
+        let storage = Storage::memory();
+        check_connection(&mut *storage.get().unwrap(), "memory-test");
+        check_history_length(&mut *storage.get().unwrap(), "memory-test");
+        check_pub_sub(&storage, "memory-test");
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_dragonfly_backend() {
+        dotenv::dotenv().ok();
+        let redis_url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379/0".to_owned());
+        let storage = Storage::dragonfly(dragonfly::new_pool(redis_url, 2).unwrap());
+        let prefix = format!("storage-test-{}", crate::models::ServerId::new());
+        check_connection(&mut *storage.get().unwrap(), &prefix);
+        check_history_length(&mut *storage.get().unwrap(), &prefix);
+        check_pub_sub(&storage, &prefix);
+
+        let room = format!("{}-room", prefix);
+        let mut connection = storage.get().unwrap();
+        connection.create_room(&room, "ci", false).unwrap();
+        let mut other = storage
+            .with_namespace(format!("{}-namespace", prefix))
+            .get()
+            .unwrap();
+        assert!(!other.room_names().unwrap().contains(&room));
+        connection.delete_room(&room).unwrap();
+    }
+}
@@ -0,0 +1,478 @@
+use super::{Backend, Connection, JoinOutcome, RenameOutcome, Subscription};
+use crate::services::{room_history::HISTORY_LENGTH, room_metadata, webhook::DEAD_LETTERS_LENGTH};
+use crate::{keys::Keys, models, Result};
+use dragonfly::{RedisPool, RedisPooledConnection, RedisPubSub, RedisScript};
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeSet, HashMap};
+
+/// Keeps the rooms in dragonfly, shared by the servers using the same database, with pooled
+/// connections.
+pub struct DragonflyBackend {
+    redis_pool: RedisPool,
+    replica_pool: Option<RedisPool>,
+}
+
+impl DragonflyBackend {
+    pub fn new(redis_pool: RedisPool) -> Self {
+        Self {
+            redis_pool,
+            replica_pool: None,
+        }
+    }
+
+    /// Sends the reads of `replica_connection` to the connections of `replica_pool`.
+    pub fn with_replicas(redis_pool: RedisPool, replica_pool: RedisPool) -> Self {
+        Self {
+            redis_pool,
+            replica_pool: Some(replica_pool),
+        }
+    }
+}
+
+impl Backend for DragonflyBackend {
+    fn connection(&self, keys: &Keys) -> Result<Box<dyn Connection>> {
+        Ok(Box::new(DragonflyConnection {
+            connection: self.redis_pool.get()?,
+            keys: keys.clone(),
+        }))
+    }
+
+    fn replica_connection(&self, keys: &Keys) -> Result<Box<dyn Connection>> {
+        match &self.replica_pool {
+            Some(replica_pool) => Ok(Box::new(DragonflyConnection {
+                connection: replica_pool.get()?,
+                keys: keys.clone(),
+            })),
+            None => self.connection(keys),
+        }
+    }
+}
+
+struct DragonflyConnection {
+    connection: RedisPooledConnection,
+    keys: Keys,
+}
+
+impl DragonflyConnection {
+    /// Reads the JSON values, skipping (and logging) the ones this build can't read, e.g. written
+    /// by a newer server.
+    fn parse_all<T: DeserializeOwned>(values: Vec<String>, what: &str, room_name: &str) -> Vec<T> {
+        values
+            .iter()
+            .filter_map(|value| match serde_json::from_str(value) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    tracing::warn!("skipped unreadable {} of {}: {}", what, room_name, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Keys of the reservations of the usernames, as the member scripts expect them.
+    fn member_keys(keys: &Keys, room_name: &str) -> [String; 3] {
+        [
+            keys.room_members(room_name),
+            keys.room_sessions(room_name),
+            keys.room_identities(room_name),
+        ]
+    }
+
+    /// Keys holding the data of a room, the metadata and the members first.
+    ///
+    /// The sequence is kept when a room is deleted, so that a room created again under the same
+    /// name doesn't reuse the ids clients may still hold as cursors.
+    fn room_keys(keys: &Keys, room_name: &str) -> Vec<String> {
+        vec![
+            keys.room_metadata(room_name),
+            keys.room_members(room_name),
+            keys.room_sessions(room_name),
+            keys.room_identities(room_name),
+            keys.room_invites(room_name),
+            keys.room_history(room_name),
+            keys.room_receipts(room_name),
+            keys.room_webhooks(room_name),
+            keys.room_dead_letters(room_name),
+            keys.room_search_index(room_name),
+            keys.room_search_terms(room_name),
+        ]
+    }
+
+    /// Deletes the room at once, unless users are in it with `unless_joined`. Returns whether it
+    /// was deleted.
+    fn delete_room_unless_joined(&mut self, room_name: &str, unless_joined: bool) -> Result<bool> {
+        let mut keys = vec![self.keys.room_directory()];
+        keys.extend(Self::room_keys(&self.keys, room_name));
+        let deleted: i64 = dragonfly::adapters::eval_script(
+            &mut self.connection,
+            &RedisScript::new(include_str!("scripts/delete_room.lua")),
+            keys,
+            &[room_name, if unless_joined { "1" } else { "0" }],
+        )?;
+        Ok(deleted == 1)
+    }
+}
+
+impl Connection for DragonflyConnection {
+    fn room_names(&mut self) -> Result<Vec<String>> {
+        let mut room_names: Vec<String> =
+            dragonfly::adapters::smembers(&mut self.connection, self.keys.room_directory())?;
+        room_names.sort();
+        Ok(room_names)
+    }
+
+    fn create_room(&mut self, room_name: &str, owner: &str, ephemeral: bool) -> Result<bool> {
+        let created: i64 = dragonfly::adapters::eval_script(
+            &mut self.connection,
+            &RedisScript::new(include_str!("scripts/create_room.lua")),
+            &[
+                self.keys.room_directory(),
+                self.keys.room_metadata(room_name),
+            ],
+            &[
+                room_name,
+                room_metadata::CREATED_AT,
+                room_metadata::OWNER,
+                room_metadata::EPHEMERAL,
+                &models::unix_millis().to_string(),
+                owner,
+                if ephemeral { "1" } else { "0" },
+            ],
+        )?;
+        Ok(created == 1)
+    }
+
+    fn delete_room(&mut self, room_name: &str) -> Result<()> {
+        self.delete_room_unless_joined(room_name, false).map(|_| ())
+    }
+
+    fn delete_empty_room(&mut self, room_name: &str) -> Result<bool> {
+        self.delete_room_unless_joined(room_name, true)
+    }
+
+    fn cleanup_room(&mut self, room_name: &str) -> Result<bool> {
+        let mut keys = vec![self.keys.room_directory()];
+        keys.extend(Self::room_keys(&self.keys, room_name));
+        let deleted: i64 = dragonfly::adapters::eval_script(
+            &mut self.connection,
+            &RedisScript::new(include_str!("scripts/cleanup_room.lua")),
+            keys,
+            &[room_name, room_metadata::EPHEMERAL, room_metadata::ARCHIVED],
+        )?;
+        Ok(deleted == 1)
+    }
+
+    fn room_metadata(&mut self, room_name: &str) -> Result<HashMap<String, String>> {
+        dragonfly::adapters::hgetall(&mut self.connection, self.keys.room_metadata(room_name))
+            .map_err(Into::into)
+    }
+
+    fn set_room_metadata(
+        &mut self,
+        room_name: &str,
+        field: &str,
+        value: Option<&str>,
+    ) -> Result<()> {
+        let key = self.keys.room_metadata(room_name);
+        match value {
+            Some(value) => dragonfly::adapters::hset(&mut self.connection, key, field, value),
+            None => dragonfly::adapters::hdel(&mut self.connection, key, field).map(|_| ()),
+        }
+        .map_err(Into::into)
+    }
+
+    fn members(&mut self, room_name: &str) -> Result<Vec<String>> {
+        let mut usernames: Vec<String> =
+            dragonfly::adapters::smembers(&mut self.connection, self.keys.room_members(room_name))?;
+        usernames.sort();
+        Ok(usernames)
+    }
+
+    fn member_count(&mut self, room_name: &str) -> Result<usize> {
+        dragonfly::adapters::scard(&mut self.connection, self.keys.room_members(room_name))
+            .map_err(Into::into)
+    }
+
+    fn join_room(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        identity_digest: &str,
+        max_members: Option<usize>,
+    ) -> Result<JoinOutcome> {
+        let outcome: String = dragonfly::adapters::eval_script(
+            &mut self.connection,
+            &RedisScript::new(include_str!("scripts/join_room.lua")),
+            &Self::member_keys(&self.keys, room_name),
+            &[
+                username.to_owned(),
+                identity_digest.to_owned(),
+                max_members.map(|max| max.to_string()).unwrap_or_default(),
+            ],
+        )?;
+        Ok(match outcome.as_str() {
+            "first-session" => JoinOutcome::FirstSession,
+            "other-session" => JoinOutcome::OtherSession,
+            "taken" => JoinOutcome::UsernameTaken,
+            _ => JoinOutcome::RoomFull,
+        })
+    }
+
+    fn leave_room(&mut self, room_name: &str, username: &str) -> Result<bool> {
+        dragonfly::adapters::eval_script(
+            &mut self.connection,
+            &RedisScript::new(include_str!("scripts/leave_room.lua")),
+            &Self::member_keys(&self.keys, room_name),
+            username,
+        )
+        .map_err(Into::into)
+    }
+
+    fn rename_member(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        new_username: &str,
+    ) -> Result<RenameOutcome> {
+        let outcome: String = dragonfly::adapters::eval_script(
+            &mut self.connection,
+            &RedisScript::new(include_str!("scripts/rename_member.lua")),
+            &[
+                self.keys.room_members(room_name),
+                self.keys.room_sessions(room_name),
+                self.keys.room_identities(room_name),
+                self.keys.room_receipts(room_name),
+                self.keys.user_read_rooms(username),
+                self.keys.user_read_rooms(new_username),
+            ],
+            &[username, new_username, room_name],
+        )?;
+        Ok(match outcome.as_str() {
+            "renamed" => RenameOutcome::Renamed,
+            "taken" => RenameOutcome::UsernameTaken,
+            _ => RenameOutcome::OtherSessions,
+        })
+    }
+
+    fn invite(&mut self, room_name: &str, username: &str, token_digest: &str) -> Result<()> {
+        dragonfly::adapters::hset(
+            &mut self.connection,
+            self.keys.room_invites(room_name),
+            username,
+            token_digest,
+        )
+        .map_err(Into::into)
+    }
+
+    fn invites(&mut self, room_name: &str) -> Result<Vec<String>> {
+        let invites: HashMap<String, String> =
+            dragonfly::adapters::hgetall(&mut self.connection, self.keys.room_invites(room_name))?;
+        let mut usernames: Vec<String> = invites.into_keys().collect();
+        usernames.sort();
+        Ok(usernames)
+    }
+
+    fn is_invited(&mut self, room_name: &str, username: &str, token_digest: &str) -> Result<bool> {
+        let stored_digest: Option<String> = dragonfly::adapters::hget(
+            &mut self.connection,
+            self.keys.room_invites(room_name),
+            username,
+        )?;
+        Ok(stored_digest.as_deref() == Some(token_digest))
+    }
+
+    fn append_message(
+        &mut self,
+        msg: &models::ChatMessage,
+        search_terms: &BTreeSet<String>,
+    ) -> Result<models::RoomMessage> {
+        let room_name = msg.room_name();
+        // The script encodes the `RoomMessage` around the id it assigns.
+        let message = models::RoomMessage::new(0, msg.clone());
+        let mut args = vec![
+            HISTORY_LENGTH.to_string(),
+            message.timestamp.to_string(),
+            serde_json::to_string(&message.msg).unwrap(),
+        ];
+        args.extend(search_terms.iter().cloned());
+        let id = dragonfly::adapters::eval_script(
+            &mut self.connection,
+            &RedisScript::new(include_str!("scripts/append_message.lua")),
+            &[
+                self.keys.room_sequence(room_name),
+                self.keys.room_history(room_name),
+                self.keys.room_search_index(room_name),
+                self.keys.room_search_terms(room_name),
+            ],
+            args,
+        )?;
+        Ok(models::RoomMessage { id, ..message })
+    }
+
+    fn messages_after(
+        &mut self,
+        room_name: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<models::RoomMessage>> {
+        let values: Vec<String> = dragonfly::adapters::zrangebyscore_limit(
+            &mut self.connection,
+            self.keys.room_history(room_name),
+            after.saturating_add(1),
+            "+inf",
+            0,
+            limit as isize,
+        )?;
+        Ok(Self::parse_all(values, "history entry", room_name))
+    }
+
+    fn last_message_id(&mut self, room_name: &str) -> Result<u64> {
+        let id: Option<u64> =
+            dragonfly::adapters::get(&mut self.connection, self.keys.room_sequence(room_name))?;
+        Ok(id.unwrap_or_default())
+    }
+
+    fn find_messages(
+        &mut self,
+        room_name: &str,
+        search_terms: &BTreeSet<String>,
+    ) -> Result<BTreeSet<u64>> {
+        let mut message_ids: Option<BTreeSet<u64>> = None;
+        for term in search_terms {
+            // Terms are alphanumeric, so `;` (following `:`) ends the range of the term.
+            let entries: Vec<String> = dragonfly::adapters::zrangebylex(
+                &mut self.connection,
+                self.keys.room_search_index(room_name),
+                format!("[{}:", term),
+                format!("({};", term),
+            )?;
+            let ids: BTreeSet<u64> = entries
+                .iter()
+                .filter_map(|entry| entry.rsplit_once(':')?.1.parse().ok())
+                .collect();
+            message_ids = Some(match message_ids {
+                Some(message_ids) => message_ids.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+        Ok(message_ids.unwrap_or_default())
+    }
+
+    fn last_read(&mut self, room_name: &str, username: &str) -> Result<Option<u64>> {
+        dragonfly::adapters::hget(
+            &mut self.connection,
+            self.keys.room_receipts(room_name),
+            username,
+        )
+        .map_err(Into::into)
+    }
+
+    fn mark_read(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        message_id: u64,
+    ) -> Result<Option<u64>> {
+        let message_id: u64 = dragonfly::adapters::eval_script(
+            &mut self.connection,
+            &RedisScript::new(include_str!("scripts/mark_read.lua")),
+            &[
+                self.keys.room_sequence(room_name),
+                self.keys.room_receipts(room_name),
+                self.keys.user_read_rooms(username),
+            ],
+            (username, message_id, room_name),
+        )?;
+        Ok(Some(message_id).filter(|message_id| *message_id > 0))
+    }
+
+    fn read_rooms(&mut self, username: &str) -> Result<Vec<String>> {
+        let mut room_names: Vec<String> = dragonfly::adapters::smembers(
+            &mut self.connection,
+            self.keys.user_read_rooms(username),
+        )?;
+        room_names.sort();
+        Ok(room_names)
+    }
+
+    fn add_webhook(&mut self, subscription: &models::WebhookSubscription) -> Result<()> {
+        let json_string = serde_json::to_string(subscription).unwrap();
+        dragonfly::adapters::hset(
+            &mut self.connection,
+            self.keys.room_webhooks(&subscription.room_name),
+            &subscription.id,
+            json_string,
+        )
+        .map_err(Into::into)
+    }
+
+    fn webhooks(&mut self, room_name: &str) -> Result<Vec<models::WebhookSubscription>> {
+        let values: Vec<String> =
+            dragonfly::adapters::hvals(&mut self.connection, self.keys.room_webhooks(room_name))?;
+        Ok(Self::parse_all(values, "webhook", room_name))
+    }
+
+    fn remove_webhook(&mut self, room_name: &str, subscription_id: &str) -> Result<bool> {
+        dragonfly::adapters::hdel(
+            &mut self.connection,
+            self.keys.room_webhooks(room_name),
+            subscription_id,
+        )
+        .map_err(Into::into)
+    }
+
+    fn push_dead_letter(
+        &mut self,
+        room_name: &str,
+        dead_letter: &models::DeadLetter,
+    ) -> Result<()> {
+        dragonfly::adapters::eval_script(
+            &mut self.connection,
+            &RedisScript::new(include_str!("scripts/push_dead_letter.lua")),
+            self.keys.room_dead_letters(room_name),
+            &[
+                serde_json::to_string(dead_letter).unwrap(),
+                DEAD_LETTERS_LENGTH.to_string(),
+            ],
+        )
+        .map_err(Into::into)
+    }
+
+    fn dead_letters(&mut self, room_name: &str) -> Result<Vec<models::DeadLetter>> {
+        let values: Vec<String> = dragonfly::adapters::lrange(
+            &mut self.connection,
+            self.keys.room_dead_letters(room_name),
+            0,
+            -1,
+        )?;
+        Ok(Self::parse_all(values, "dead letter", room_name))
+    }
+
+    fn publish(&mut self, channel_name: &str, payload: &[u8]) -> Result<()> {
+        dragonfly::adapters::publish(
+            &mut self.connection,
+            self.keys.channel(channel_name),
+            payload,
+        )
+        .map_err(Into::into)
+    }
+
+    fn subscribe<'a>(&'a mut self, channel_name: &str) -> Result<Box<dyn Subscription + 'a>> {
+        // Messages may be apart for longer than the command timeout of the pool.
+        self.connection
+            .set_read_timeout(None)
+            .map_err(dragonfly::Error::from)?;
+        let pub_sub =
+            dragonfly::adapters::subscribe(&mut self.connection, self.keys.channel(channel_name))?;
+        Ok(Box::new(DragonflySubscription(pub_sub)))
+    }
+}
+
+struct DragonflySubscription<'a>(RedisPubSub<'a>);
+
+impl Subscription for DragonflySubscription<'_> {
+    fn next_payload(&mut self) -> Result<Vec<u8>> {
+        let msg = self.0.get_message().map_err(dragonfly::Error::from)?;
+        Ok(msg.get_payload_bytes().to_vec())
+    }
+}
@@ -0,0 +1,491 @@
+use super::{Backend, Connection, JoinOutcome, RenameOutcome, Subscription};
+use crate::services::{room_history::HISTORY_LENGTH, room_metadata, webhook::DEAD_LETTERS_LENGTH};
+use crate::{keys::Keys, models, Error, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// Reservation of a username in a room.
+struct Member {
+    identity_digest: String,
+    sessions: u64,
+}
+
+#[derive(Default)]
+struct Room {
+    metadata: HashMap<String, String>,
+    members: BTreeMap<String, Member>,
+    /// Digest of the invite token of each invited user.
+    invites: BTreeMap<String, String>,
+    /// Id of the latest message.
+    sequence: u64,
+    history: BTreeMap<u64, models::RoomMessage>,
+    /// Ids of the messages of the history containing each search term.
+    search_index: HashMap<String, BTreeSet<u64>>,
+    receipts: HashMap<String, u64>,
+    webhooks: BTreeMap<String, models::WebhookSubscription>,
+    dead_letters: VecDeque<models::DeadLetter>,
+}
+
+impl Room {
+    fn is_ephemeral(&self) -> bool {
+        self.metadata.contains_key(room_metadata::EPHEMERAL)
+    }
+
+    fn is_archived(&self) -> bool {
+        self.metadata.contains_key(room_metadata::ARCHIVED)
+    }
+
+    /// Drops the oldest messages beyond the history length, with their search index entries.
+    fn trim_history(&mut self) {
+        while self.history.len() > HISTORY_LENGTH as usize {
+            let id = match self.history.keys().next() {
+                Some(id) => *id,
+                None => break,
+            };
+            self.history.remove(&id);
+            self.search_index.retain(|_, ids| {
+                ids.remove(&id);
+                !ids.is_empty()
+            });
+        }
+    }
+}
+
+#[derive(Default)]
+struct Database {
+    /// Names of the existing rooms.
+    directory: BTreeSet<String>,
+    rooms: HashMap<String, Room>,
+    /// Rooms in which each user has a read receipt.
+    read_rooms: HashMap<String, BTreeSet<String>>,
+    /// Senders of the subscriptions to each channel.
+    subscribers: HashMap<String, Vec<mpsc::Sender<Vec<u8>>>>,
+}
+
+impl Database {
+    /// Reads the room, empty if missing.
+    fn room<T>(&self, room_name: &str, f: impl FnOnce(&Room) -> T) -> T {
+        match self.rooms.get(room_name) {
+            Some(room) => f(room),
+            None => f(&Room::default()),
+        }
+    }
+
+    /// Drops everything of the room but its sequence, so that ids are never reused.
+    fn delete_room(&mut self, room_name: &str) {
+        self.directory.remove(room_name);
+        if let Some(room) = self.rooms.get_mut(room_name) {
+            *room = Room {
+                sequence: room.sequence,
+                ..Room::default()
+            };
+        }
+    }
+
+    /// Changes the room, created empty if missing.
+    fn room_mut<T>(&mut self, room_name: &str, f: impl FnOnce(&mut Room) -> T) -> T {
+        f(self.rooms.entry(room_name.to_owned()).or_default())
+    }
+}
+
+/// Keeps the rooms in the process: servers don't share anything and nothing survives a restart.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    database: Arc<Mutex<Database>>,
+}
+
+impl Backend for MemoryBackend {
+    fn connection(&self, _keys: &Keys) -> Result<Box<dyn Connection>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+impl MemoryBackend {
+    /// Runs `f` with the database locked, which makes every operation atomic.
+    fn with_database<T>(&self, f: impl FnOnce(&mut Database) -> T) -> Result<T> {
+        Ok(f(&mut self.database.lock().unwrap()))
+    }
+}
+
+impl Connection for MemoryBackend {
+    fn room_names(&mut self) -> Result<Vec<String>> {
+        self.with_database(|database| database.directory.iter().cloned().collect())
+    }
+
+    fn create_room(&mut self, room_name: &str, owner: &str, ephemeral: bool) -> Result<bool> {
+        self.with_database(|database| {
+            database.directory.insert(room_name.to_owned());
+            database.room_mut(room_name, |room| {
+                if room.metadata.contains_key(room_metadata::CREATED_AT) {
+                    return false;
+                }
+                let metadata = &mut room.metadata;
+                metadata.insert(
+                    room_metadata::CREATED_AT.to_owned(),
+                    models::unix_millis().to_string(),
+                );
+                metadata.insert(room_metadata::OWNER.to_owned(), owner.to_owned());
+                if ephemeral {
+                    metadata.insert(room_metadata::EPHEMERAL.to_owned(), "1".to_owned());
+                }
+                true
+            })
+        })
+    }
+
+    fn delete_room(&mut self, room_name: &str) -> Result<()> {
+        self.with_database(|database| database.delete_room(room_name))
+    }
+
+    fn delete_empty_room(&mut self, room_name: &str) -> Result<bool> {
+        self.with_database(|database| {
+            let empty = database.room(room_name, |room| room.members.is_empty());
+            if empty {
+                database.delete_room(room_name);
+            }
+            empty
+        })
+    }
+
+    fn cleanup_room(&mut self, room_name: &str) -> Result<bool> {
+        self.with_database(|database| {
+            let abandoned = database.room(room_name, |room| {
+                room.is_ephemeral() && !room.is_archived() && room.members.is_empty()
+            });
+            if abandoned {
+                database.delete_room(room_name);
+            }
+            abandoned
+        })
+    }
+
+    fn room_metadata(&mut self, room_name: &str) -> Result<HashMap<String, String>> {
+        self.with_database(|database| database.room(room_name, |room| room.metadata.clone()))
+    }
+
+    fn set_room_metadata(
+        &mut self,
+        room_name: &str,
+        field: &str,
+        value: Option<&str>,
+    ) -> Result<()> {
+        self.with_database(|database| {
+            database.room_mut(room_name, |room| match value {
+                Some(value) => {
+                    room.metadata.insert(field.to_owned(), value.to_owned());
+                }
+                None => {
+                    room.metadata.remove(field);
+                }
+            })
+        })
+    }
+
+    fn members(&mut self, room_name: &str) -> Result<Vec<String>> {
+        self.with_database(|database| {
+            database.room(room_name, |room| room.members.keys().cloned().collect())
+        })
+    }
+
+    fn member_count(&mut self, room_name: &str) -> Result<usize> {
+        self.with_database(|database| database.room(room_name, |room| room.members.len()))
+    }
+
+    fn join_room(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        identity_digest: &str,
+        max_members: Option<usize>,
+    ) -> Result<JoinOutcome> {
+        self.with_database(|database| {
+            database.room_mut(room_name, |room| {
+                if let Some(member) = room.members.get_mut(username) {
+                    if member.identity_digest != identity_digest {
+                        return JoinOutcome::UsernameTaken;
+                    }
+                    member.sessions += 1;
+                    return JoinOutcome::OtherSession;
+                }
+                if max_members.map_or(false, |max_members| room.members.len() >= max_members) {
+                    return JoinOutcome::RoomFull;
+                }
+                room.members.insert(
+                    username.to_owned(),
+                    Member {
+                        identity_digest: identity_digest.to_owned(),
+                        sessions: 1,
+                    },
+                );
+                JoinOutcome::FirstSession
+            })
+        })
+    }
+
+    fn leave_room(&mut self, room_name: &str, username: &str) -> Result<bool> {
+        self.with_database(|database| {
+            let room = match database.rooms.get_mut(room_name) {
+                Some(room) => room,
+                None => return false,
+            };
+            let member = match room.members.get_mut(username) {
+                Some(member) => member,
+                None => return false,
+            };
+            member.sessions = member.sessions.saturating_sub(1);
+            if member.sessions > 0 {
+                return false;
+            }
+            room.members.remove(username);
+            true
+        })
+    }
+
+    fn rename_member(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        new_username: &str,
+    ) -> Result<RenameOutcome> {
+        self.with_database(|database| {
+            let outcome = database.room_mut(room_name, |room| {
+                if room
+                    .members
+                    .get(username)
+                    .map_or(false, |member| member.sessions > 1)
+                {
+                    return RenameOutcome::OtherSessions;
+                }
+                if room.members.contains_key(new_username) {
+                    return RenameOutcome::UsernameTaken;
+                }
+                let member = room.members.remove(username).unwrap_or(Member {
+                    identity_digest: String::new(),
+                    sessions: 1,
+                });
+                room.members.insert(new_username.to_owned(), member);
+                if let Some(message_id) = room.receipts.remove(username) {
+                    room.receipts.insert(new_username.to_owned(), message_id);
+                }
+                RenameOutcome::Renamed
+            });
+            if outcome == RenameOutcome::Renamed
+                && database
+                    .read_rooms
+                    .get_mut(username)
+                    .map_or(false, |room_names| room_names.remove(room_name))
+            {
+                database
+                    .read_rooms
+                    .entry(new_username.to_owned())
+                    .or_default()
+                    .insert(room_name.to_owned());
+            }
+            outcome
+        })
+    }
+
+    fn invite(&mut self, room_name: &str, username: &str, token_digest: &str) -> Result<()> {
+        self.with_database(|database| {
+            database.room_mut(room_name, |room| {
+                room.invites
+                    .insert(username.to_owned(), token_digest.to_owned());
+            })
+        })
+    }
+
+    fn invites(&mut self, room_name: &str) -> Result<Vec<String>> {
+        self.with_database(|database| {
+            database.room(room_name, |room| room.invites.keys().cloned().collect())
+        })
+    }
+
+    fn is_invited(&mut self, room_name: &str, username: &str, token_digest: &str) -> Result<bool> {
+        self.with_database(|database| {
+            database.room(room_name, |room| {
+                room.invites.get(username).map(String::as_str) == Some(token_digest)
+            })
+        })
+    }
+
+    fn append_message(
+        &mut self,
+        msg: &models::ChatMessage,
+        search_terms: &BTreeSet<String>,
+    ) -> Result<models::RoomMessage> {
+        self.with_database(|database| {
+            database.room_mut(msg.room_name(), |room| {
+                room.sequence += 1;
+                let message = models::RoomMessage::new(room.sequence, msg.clone());
+                room.history.insert(message.id, message.clone());
+                for term in search_terms {
+                    room.search_index
+                        .entry(term.clone())
+                        .or_default()
+                        .insert(message.id);
+                }
+                room.trim_history();
+                message
+            })
+        })
+    }
+
+    fn messages_after(
+        &mut self,
+        room_name: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<models::RoomMessage>> {
+        self.with_database(|database| {
+            database.room(room_name, |room| {
+                room.history
+                    .range(after.saturating_add(1)..)
+                    .take(limit)
+                    .map(|(_, message)| message.clone())
+                    .collect()
+            })
+        })
+    }
+
+    fn last_message_id(&mut self, room_name: &str) -> Result<u64> {
+        self.with_database(|database| database.room(room_name, |room| room.sequence))
+    }
+
+    fn find_messages(
+        &mut self,
+        room_name: &str,
+        search_terms: &BTreeSet<String>,
+    ) -> Result<BTreeSet<u64>> {
+        self.with_database(|database| {
+            database.room(room_name, |room| {
+                let mut message_ids: Option<BTreeSet<u64>> = None;
+                for term in search_terms {
+                    let ids = room.search_index.get(term).cloned().unwrap_or_default();
+                    message_ids = Some(match message_ids {
+                        Some(message_ids) => message_ids.intersection(&ids).copied().collect(),
+                        None => ids,
+                    });
+                }
+                message_ids.unwrap_or_default()
+            })
+        })
+    }
+
+    fn last_read(&mut self, room_name: &str, username: &str) -> Result<Option<u64>> {
+        self.with_database(|database| {
+            database.room(room_name, |room| room.receipts.get(username).copied())
+        })
+    }
+
+    fn mark_read(
+        &mut self,
+        room_name: &str,
+        username: &str,
+        message_id: u64,
+    ) -> Result<Option<u64>> {
+        self.with_database(|database| {
+            let read = database.room_mut(room_name, |room| {
+                let message_id = message_id.min(room.sequence);
+                if room.receipts.get(username).copied().unwrap_or_default() >= message_id {
+                    return None;
+                }
+                room.receipts.insert(username.to_owned(), message_id);
+                Some(message_id)
+            });
+            if read.is_some() {
+                database
+                    .read_rooms
+                    .entry(username.to_owned())
+                    .or_default()
+                    .insert(room_name.to_owned());
+            }
+            read
+        })
+    }
+
+    fn read_rooms(&mut self, username: &str) -> Result<Vec<String>> {
+        self.with_database(|database| {
+            database
+                .read_rooms
+                .get(username)
+                .map(|room_names| room_names.iter().cloned().collect())
+                .unwrap_or_default()
+        })
+    }
+
+    fn add_webhook(&mut self, subscription: &models::WebhookSubscription) -> Result<()> {
+        self.with_database(|database| {
+            database.room_mut(&subscription.room_name, |room| {
+                room.webhooks
+                    .insert(subscription.id.clone(), subscription.clone());
+            })
+        })
+    }
+
+    fn webhooks(&mut self, room_name: &str) -> Result<Vec<models::WebhookSubscription>> {
+        self.with_database(|database| {
+            database.room(room_name, |room| room.webhooks.values().cloned().collect())
+        })
+    }
+
+    fn remove_webhook(&mut self, room_name: &str, subscription_id: &str) -> Result<bool> {
+        self.with_database(|database| {
+            database.room_mut(room_name, |room| {
+                room.webhooks.remove(subscription_id).is_some()
+            })
+        })
+    }
+
+    fn push_dead_letter(
+        &mut self,
+        room_name: &str,
+        dead_letter: &models::DeadLetter,
+    ) -> Result<()> {
+        self.with_database(|database| {
+            database.room_mut(room_name, |room| {
+                room.dead_letters.push_back(dead_letter.clone());
+                while room.dead_letters.len() > DEAD_LETTERS_LENGTH as usize {
+                    room.dead_letters.pop_front();
+                }
+            })
+        })
+    }
+
+    fn dead_letters(&mut self, room_name: &str) -> Result<Vec<models::DeadLetter>> {
+        self.with_database(|database| {
+            database.room(room_name, |room| {
+                room.dead_letters.iter().cloned().collect()
+            })
+        })
+    }
+
+    fn publish(&mut self, channel_name: &str, payload: &[u8]) -> Result<()> {
+        self.with_database(|database| {
+            if let Some(senders) = database.subscribers.get_mut(channel_name) {
+                senders.retain(|sender| sender.send(payload.to_vec()).is_ok());
+            }
+        })
+    }
+
+    fn subscribe<'a>(&'a mut self, channel_name: &str) -> Result<Box<dyn Subscription + 'a>> {
+        let (sender, receiver) = mpsc::channel();
+        self.with_database(|database| {
+            database
+                .subscribers
+                .entry(channel_name.to_owned())
+                .or_default()
+                .push(sender);
+        })?;
+        Ok(Box::new(MemorySubscription(receiver)))
+    }
+}
+
+struct MemorySubscription(mpsc::Receiver<Vec<u8>>);
+
+impl Subscription for MemorySubscription {
+    fn next_payload(&mut self) -> Result<Vec<u8>> {
+        self.0
+            .recv()
+            .map_err(|e| Error::SystemError { cause: e.into() })
+    }
+}
@@ -0,0 +1,31 @@
+-- Assigns the next id of the room to a message and appends it to the history, indexing it for
+-- search, then trims the history and the index entries of the messages dropping out of it.
+-- KEYS: sequence, history, search index, search terms of the room
+-- ARGV: history length, timestamp, JSON encoded chat message, then the search terms of the message
+-- Returns the id of the message.
+local id = redis.call('INCR', KEYS[1])
+local history_length = tonumber(ARGV[1])
+local message_id = string.format('%d', id)
+local message = '{"id":' .. message_id .. ',"timestamp":' .. ARGV[2] .. ',"msg":' .. ARGV[3] .. '}'
+redis.call('ZADD', KEYS[2], id, message)
+if #ARGV > 3 then
+    local terms = {}
+    for i = 4, #ARGV do
+        redis.call('ZADD', KEYS[3], 0, ARGV[i] .. ':' .. message_id)
+        terms[#terms + 1] = ARGV[i]
+    end
+    redis.call('HSET', KEYS[4], message_id, table.concat(terms, ' '))
+end
+local dropped = redis.call('ZRANGE', KEYS[2], 0, -history_length - 1, 'WITHSCORES')
+for i = 2, #dropped, 2 do
+    local dropped_id = string.format('%d', tonumber(dropped[i]))
+    local terms = redis.call('HGET', KEYS[4], dropped_id)
+    if terms then
+        for term in string.gmatch(terms, '%S+') do
+            redis.call('ZREM', KEYS[3], term .. ':' .. dropped_id)
+        end
+        redis.call('HDEL', KEYS[4], dropped_id)
+    end
+end
+redis.call('ZREMRANGEBYRANK', KEYS[2], 0, -history_length - 1)
+return id
@@ -0,0 +1,14 @@
+-- Deletes an empty ephemeral room which isn't archived, keeping its message sequence.
+-- KEYS: room directory, metadata, members of the room, then the other keys to delete
+-- ARGV: room name, ephemeral and archived metadata fields
+-- Returns 1 when the room was deleted.
+if not redis.call('HGET', KEYS[2], ARGV[2])
+    or redis.call('HGET', KEYS[2], ARGV[3])
+    or redis.call('SCARD', KEYS[3]) > 0 then
+    return 0
+end
+redis.call('SREM', KEYS[1], ARGV[1])
+for i = 2, #KEYS do
+    redis.call('DEL', KEYS[i])
+end
+return 1
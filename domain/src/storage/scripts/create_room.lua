@@ -0,0 +1,14 @@
+-- Creates the room unless it exists, and lists it in the directory either way.
+-- KEYS: room directory, metadata of the room
+-- ARGV: room name, created at, owner and ephemeral metadata fields, then the creation time, the
+--       owner, and 1 for an ephemeral room
+-- Returns 1 when the room was created.
+redis.call('SADD', KEYS[1], ARGV[1])
+if redis.call('HSETNX', KEYS[2], ARGV[2], ARGV[5]) == 0 then
+    return 0
+end
+redis.call('HSET', KEYS[2], ARGV[3], ARGV[6])
+if ARGV[7] == '1' then
+    redis.call('HSET', KEYS[2], ARGV[4], '1')
+end
+return 1
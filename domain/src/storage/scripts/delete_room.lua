@@ -0,0 +1,12 @@
+-- Deletes the room, keeping its message sequence.
+-- KEYS: room directory, metadata, members of the room, then the other keys to delete
+-- ARGV: room name, 1 to keep the room when users are in it
+-- Returns 1 when the room was deleted.
+if ARGV[2] == '1' and redis.call('SCARD', KEYS[3]) > 0 then
+    return 0
+end
+redis.call('SREM', KEYS[1], ARGV[1])
+for i = 2, #KEYS do
+    redis.call('DEL', KEYS[i])
+end
+return 1
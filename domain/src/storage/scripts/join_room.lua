@@ -0,0 +1,18 @@
+-- Reserves the username for the first session of a user, or counts one more session of the user
+-- holding it.
+-- KEYS: members, sessions, identities of the room
+-- ARGV: username, identity digest, maximum number of members ('' for no limit)
+if redis.call('SISMEMBER', KEYS[1], ARGV[1]) == 1 then
+    if redis.call('HGET', KEYS[3], ARGV[1]) ~= ARGV[2] then
+        return 'taken'
+    end
+    redis.call('HINCRBY', KEYS[2], ARGV[1], 1)
+    return 'other-session'
+end
+if ARGV[3] ~= '' and redis.call('SCARD', KEYS[1]) >= tonumber(ARGV[3]) then
+    return 'full'
+end
+redis.call('SADD', KEYS[1], ARGV[1])
+redis.call('HSET', KEYS[3], ARGV[1], ARGV[2])
+redis.call('HSET', KEYS[2], ARGV[1], 1)
+return 'first-session'
@@ -0,0 +1,13 @@
+-- Closes a session of the user, releasing the username with the last one.
+-- KEYS: members, sessions, identities of the room
+-- ARGV: username
+-- Returns 1 when the user left the room, 0 when other sessions remain or the user is not in the
+-- room (e.g. it was deleted meanwhile).
+if redis.call('SISMEMBER', KEYS[1], ARGV[1]) == 0
+    or redis.call('HINCRBY', KEYS[2], ARGV[1], -1) > 0 then
+    return 0
+end
+redis.call('HDEL', KEYS[2], ARGV[1])
+redis.call('HDEL', KEYS[3], ARGV[1])
+redis.call('SREM', KEYS[1], ARGV[1])
+return 1
@@ -0,0 +1,11 @@
+-- Moves the read receipt of a user forward, up to the latest message of the room.
+-- KEYS: sequence, receipts of the room, rooms with a read receipt of the user
+-- ARGV: username, message id, room name
+-- Returns the new read position, 0 when the user had read that far.
+local message_id = math.min(tonumber(ARGV[2]), tonumber(redis.call('GET', KEYS[1]) or '0'))
+if message_id <= tonumber(redis.call('HGET', KEYS[2], ARGV[1]) or '0') then
+    return 0
+end
+redis.call('HSET', KEYS[2], ARGV[1], message_id)
+redis.call('SADD', KEYS[3], ARGV[3])
+return message_id
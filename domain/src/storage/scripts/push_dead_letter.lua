@@ -0,0 +1,5 @@
+-- Appends a dead letter to those of the room, dropping the oldest beyond the limit.
+-- KEYS: dead letters of the room
+-- ARGV: JSON encoded dead letter, number of dead letters to keep
+redis.call('RPUSH', KEYS[1], ARGV[1])
+redis.call('LTRIM', KEYS[1], -tonumber(ARGV[2]), -1)
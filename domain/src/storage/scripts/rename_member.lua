@@ -0,0 +1,22 @@
+-- Moves the reservation and the read receipt of a username to a new one.
+-- KEYS: members, sessions, identities, receipts of the room, then the rooms with a read receipt
+--   of the username and of the new username
+-- ARGV: username, new username, room name
+if tonumber(redis.call('HGET', KEYS[2], ARGV[1]) or '0') > 1 then
+    return 'other-sessions'
+end
+if redis.call('SADD', KEYS[1], ARGV[2]) == 0 then
+    return 'taken'
+end
+for i = 2, 4 do
+    local value = redis.call('HGET', KEYS[i], ARGV[1])
+    if value then
+        redis.call('HSET', KEYS[i], ARGV[2], value)
+    end
+    redis.call('HDEL', KEYS[i], ARGV[1])
+end
+if redis.call('SREM', KEYS[5], ARGV[3]) == 1 then
+    redis.call('SADD', KEYS[6], ARGV[3])
+end
+redis.call('SREM', KEYS[1], ARGV[1])
+return 'renamed'
@@ -1,5 +1,5 @@
-use crate::{RedisConnection, Result};
-use redis::{Commands, ConnectionLike, FromRedisValue, PubSub, ToRedisArgs};
+use crate::{RedisConnection, RedisPubSub, Result};
+use redis::{Commands, ConnectionLike, FromRedisValue, Script, ToRedisArgs};
 
 pub fn health_check(conn: &mut RedisConnection) -> bool {
     conn.check_connection()
@@ -13,10 +13,8 @@ pub fn publish<K: ToRedisArgs, V: ToRedisArgs>(
     conn.publish(channel, value).map_err(Into::into)
 }
 
-pub fn subscribe<K: ToRedisArgs>(conn: &mut RedisConnection, channel: K) -> Result<PubSub> {
-    let mut pubsub = conn.as_pubsub();
-    pubsub.subscribe(channel)?;
-    Ok(pubsub)
+pub fn subscribe<K: ToRedisArgs>(conn: &mut RedisConnection, channel: K) -> Result<RedisPubSub> {
+    conn.subscribe(channel).map_err(Into::into)
 }
 
 pub fn getset<K: ToRedisArgs, V: ToRedisArgs + FromRedisValue>(
@@ -39,11 +37,16 @@ pub fn set<K: ToRedisArgs, V: ToRedisArgs>(
     conn.set(key, value).map_err(Into::into)
 }
 
+pub fn del<K: ToRedisArgs>(conn: &mut RedisConnection, key: K) -> Result<()> {
+    conn.del(key).map_err(Into::into)
+}
+
+/// Returns whether the value was not a member yet.
 pub fn sadd<K: ToRedisArgs, V: ToRedisArgs>(
     conn: &mut RedisConnection,
     key: K,
     value: V,
-) -> Result<()> {
+) -> Result<bool> {
     conn.sadd(key, value).map_err(Into::into)
 }
 
@@ -70,7 +73,164 @@ pub fn smembers<K: ToRedisArgs, V: FromRedisValue>(
     conn.smembers(key).map_err(Into::into)
 }
 
+pub fn scard<K: ToRedisArgs>(conn: &mut RedisConnection, key: K) -> Result<usize> {
+    conn.scard(key).map_err(Into::into)
+}
+
+pub fn incr<K: ToRedisArgs>(conn: &mut RedisConnection, key: K) -> Result<u64> {
+    conn.incr(key, 1).map_err(Into::into)
+}
+
+pub fn zadd<K: ToRedisArgs, M: ToRedisArgs, S: ToRedisArgs>(
+    conn: &mut RedisConnection,
+    key: K,
+    member: M,
+    score: S,
+) -> Result<()> {
+    conn.zadd(key, member, score).map_err(Into::into)
+}
+
+pub fn zrangebyscore_limit<K: ToRedisArgs, M: ToRedisArgs, MM: ToRedisArgs, V: FromRedisValue>(
+    conn: &mut RedisConnection,
+    key: K,
+    min: M,
+    max: MM,
+    offset: isize,
+    count: isize,
+) -> Result<Vec<V>> {
+    conn.zrangebyscore_limit(key, min, max, offset, count)
+        .map_err(Into::into)
+}
+
+pub fn zrangebyscore<K: ToRedisArgs, M: ToRedisArgs, MM: ToRedisArgs, V: FromRedisValue>(
+    conn: &mut RedisConnection,
+    key: K,
+    min: M,
+    max: MM,
+) -> Result<Vec<V>> {
+    conn.zrangebyscore(key, min, max).map_err(Into::into)
+}
+
+pub fn zrangebylex<K: ToRedisArgs, M: ToRedisArgs, MM: ToRedisArgs, V: FromRedisValue>(
+    conn: &mut RedisConnection,
+    key: K,
+    min: M,
+    max: MM,
+) -> Result<Vec<V>> {
+    conn.zrangebylex(key, min, max).map_err(Into::into)
+}
+
+pub fn zremrangebyscore<K: ToRedisArgs, M: ToRedisArgs, MM: ToRedisArgs>(
+    conn: &mut RedisConnection,
+    key: K,
+    min: M,
+    max: MM,
+) -> Result<()> {
+    conn.zrembyscore(key, min, max).map_err(Into::into)
+}
+
+pub fn zremrangebyrank<K: ToRedisArgs>(
+    conn: &mut RedisConnection,
+    key: K,
+    start: isize,
+    stop: isize,
+) -> Result<()> {
+    conn.zremrangebyrank(key, start, stop).map_err(Into::into)
+}
+
+pub fn hset<K: ToRedisArgs, F: ToRedisArgs, V: ToRedisArgs>(
+    conn: &mut RedisConnection,
+    key: K,
+    field: F,
+    value: V,
+) -> Result<()> {
+    conn.hset(key, field, value).map_err(Into::into)
+}
+
+pub fn hget<K: ToRedisArgs, F: ToRedisArgs, V: FromRedisValue>(
+    conn: &mut RedisConnection,
+    key: K,
+    field: F,
+) -> Result<V> {
+    conn.hget(key, field).map_err(Into::into)
+}
+
+/// Returns whether the field was set.
+pub fn hsetnx<K: ToRedisArgs, F: ToRedisArgs, V: ToRedisArgs>(
+    conn: &mut RedisConnection,
+    key: K,
+    field: F,
+    value: V,
+) -> Result<bool> {
+    conn.hset_nx(key, field, value).map_err(Into::into)
+}
+
+pub fn hgetall<K: ToRedisArgs, V: FromRedisValue>(conn: &mut RedisConnection, key: K) -> Result<V> {
+    conn.hgetall(key).map_err(Into::into)
+}
+
+pub fn hincrby<K: ToRedisArgs, F: ToRedisArgs>(
+    conn: &mut RedisConnection,
+    key: K,
+    field: F,
+    delta: i64,
+) -> Result<i64> {
+    conn.hincr(key, field, delta).map_err(Into::into)
+}
+
+pub fn hdel<K: ToRedisArgs, F: ToRedisArgs>(
+    conn: &mut RedisConnection,
+    key: K,
+    field: F,
+) -> Result<bool> {
+    conn.hdel(key, field).map_err(Into::into)
+}
+
+pub fn hvals<K: ToRedisArgs, V: FromRedisValue>(
+    conn: &mut RedisConnection,
+    key: K,
+) -> Result<Vec<V>> {
+    conn.hvals(key).map_err(Into::into)
+}
+
+pub fn rpush<K: ToRedisArgs, V: ToRedisArgs>(
+    conn: &mut RedisConnection,
+    key: K,
+    value: V,
+) -> Result<()> {
+    conn.rpush(key, value).map_err(Into::into)
+}
+
+pub fn ltrim<K: ToRedisArgs>(
+    conn: &mut RedisConnection,
+    key: K,
+    start: isize,
+    stop: isize,
+) -> Result<()> {
+    conn.ltrim(key, start, stop).map_err(Into::into)
+}
+
+pub fn lrange<K: ToRedisArgs, V: FromRedisValue>(
+    conn: &mut RedisConnection,
+    key: K,
+    start: isize,
+    stop: isize,
+) -> Result<Vec<V>> {
+    conn.lrange(key, start, stop).map_err(Into::into)
+}
+
+/// Runs the Lua script atomically, loading it into the server first when it doesn't know it yet.
+pub fn eval_script<K: ToRedisArgs, A: ToRedisArgs, T: FromRedisValue>(
+    conn: &mut RedisConnection,
+    script: &Script,
+    keys: K,
+    args: A,
+) -> Result<T> {
+    script.key(keys).arg(args).invoke(conn).map_err(Into::into)
+}
+
 #[cfg(test)]
+#[allow(clippy::or_fun_call, clippy::redundant_clone, clippy::let_unit_value)]
 mod test {
     use super::*;
     use crate::*;
@@ -1,8 +1,10 @@
-use crate::{RedisConnection, Result};
-use redis::Client;
+use crate::{ConnectionManager, PoolConfig, RedisConnection, Result};
 
 pub fn establish_connection<S: Into<String>>(redis_url: S) -> Result<RedisConnection> {
-    let url_string = redis_url.into();
-    let client = Client::open(url_string.as_str())?;
-    Ok(client.get_connection()?)
+    establish_connection_with_config(&PoolConfig::new(redis_url))
+}
+
+/// Opens a connection with the credentials, TLS settings and timeouts of `config`.
+pub fn establish_connection_with_config(config: &PoolConfig) -> Result<RedisConnection> {
+    Ok(ConnectionManager::new(config)?.connect()?)
 }
@@ -1,12 +1,16 @@
 extern crate redis;
 
 mod conn;
+mod connection;
 mod error;
 mod pool;
+mod sentinel;
 
 pub use conn::*;
+pub use connection::{RedisConnection, RedisPubSub, TlsConnection};
 pub use error::*;
 pub use pool::*;
+pub use sentinel::{NodeRole, SentinelConfig};
 
 pub mod adapters;
 
@@ -14,7 +18,8 @@ pub use redis::{FromRedisValue, RedisResult, RedisWrite, ToRedisArgs};
 pub type RedisR2D2Error = r2d2::Error;
 pub type RedisValue = redis::Value;
 pub type RedisErrorKind = redis::ErrorKind;
-pub type RedisConnection = redis::Connection;
 pub type RedisClient = redis::Client;
-pub type RedisPool = r2d2::Pool<RedisClient>;
+pub type RedisPool = r2d2::Pool<ConnectionManager>;
+pub type RedisPooledConnection = r2d2::PooledConnection<ConnectionManager>;
+pub type RedisScript = redis::Script;
 pub type Result<T> = core::result::Result<T, Error>;
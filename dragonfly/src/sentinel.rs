@@ -0,0 +1,334 @@
+//! Discovery of the nodes of a replicated dragonfly through Redis Sentinel.
+
+use crate::connection::Connector;
+use crate::{RedisConnection, Result};
+use redis::{ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, RedisResult};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Sentinels monitoring the primary and its replicas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentinelConfig {
+    /// `redis://` (or `rediss://`) urls of the sentinels, asked in order; they may carry the
+    /// credentials of the sentinels.
+    pub urls: Vec<String>,
+    /// Name the sentinels monitor the primary under.
+    pub master_name: String,
+}
+
+/// Which nodes the connections of a pool go to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeRole {
+    /// The current primary, for writes.
+    Primary,
+    /// A replica, for reads tolerating a slight delay; the primary when no replica is available.
+    Replica,
+}
+
+/// Finds the address of the node of a role, asking the sentinels each time.
+#[derive(Debug)]
+pub(crate) struct NodeResolver {
+    sentinels: Vec<ConnectionInfo>,
+    master_name: String,
+    role: NodeRole,
+    /// Opens the connections to the sentinels, whose replies are bounded by the connection
+    /// timeout.
+    connector: Connector,
+    /// Used while no sentinel answers.
+    last_primary: Mutex<Option<(String, u16)>>,
+    next_replica: AtomicUsize,
+}
+
+impl NodeResolver {
+    pub(crate) fn new(
+        config: &SentinelConfig,
+        role: NodeRole,
+        connector: Connector,
+    ) -> Result<Self> {
+        if config.urls.is_empty() {
+            return Err(redis::RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "at least one sentinel is required",
+            ))
+            .into());
+        }
+        let sentinels = config
+            .urls
+            .iter()
+            .map(|url| url.as_str().into_connection_info())
+            .collect::<RedisResult<_>>()?;
+        Ok(Self {
+            sentinels,
+            master_name: config.master_name.clone(),
+            role,
+            connector,
+            last_primary: Mutex::new(None),
+            next_replica: AtomicUsize::new(0),
+        })
+    }
+
+    pub(crate) fn role(&self) -> NodeRole {
+        self.role
+    }
+
+    /// Returns the address of a node, with the TLS settings of `template`.
+    pub(crate) fn resolve(&self, template: &ConnectionAddr) -> RedisResult<ConnectionAddr> {
+        let (host, port) = match self.role {
+            NodeRole::Primary => self.primary()?,
+            NodeRole::Replica => match self.replicas() {
+                Ok(replicas) if !replicas.is_empty() => {
+                    let next = self.next_replica.fetch_add(1, Ordering::Relaxed);
+                    replicas[next % replicas.len()].clone()
+                }
+                _ => self.primary()?,
+            },
+        };
+        Ok(match template {
+            ConnectionAddr::TcpTls { insecure, .. } => ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure: *insecure,
+            },
+            _ => ConnectionAddr::Tcp(host, port),
+        })
+    }
+
+    fn primary(&self) -> RedisResult<(String, u16)> {
+        match self.ask(|connection| {
+            redis::cmd("SENTINEL")
+                .arg("get-master-addr-by-name")
+                .arg(&self.master_name)
+                .query::<Option<(String, u16)>>(connection)
+        }) {
+            Ok(Some(primary)) => {
+                *self.last_primary.lock().unwrap() = Some(primary.clone());
+                Ok(primary)
+            }
+            Ok(None) => Err(redis::RedisError::from((
+                ErrorKind::ResponseError,
+                "the sentinels don't monitor the master name",
+                self.master_name.clone(),
+            ))),
+            Err(e) => self.last_primary.lock().unwrap().clone().ok_or(e),
+        }
+    }
+
+    fn replicas(&self) -> RedisResult<Vec<(String, u16)>> {
+        self.ask(|connection| {
+            redis::cmd("SENTINEL")
+                .arg("replicas")
+                .arg(&self.master_name)
+                .query(connection)
+                .map(healthy_replicas)
+        })
+    }
+
+    /// Runs the request on the first sentinel answering.
+    fn ask<T>(&self, request: impl Fn(&mut RedisConnection) -> RedisResult<T>) -> RedisResult<T> {
+        let mut last_error = None;
+        for sentinel in &self.sentinels {
+            let answer = self.connector.connect(sentinel).and_then(|mut connection| {
+                connection.set_read_timeout(self.connector.timeout())?;
+                request(&mut connection)
+            });
+            match answer {
+                Ok(answer) => return Ok(answer),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap())
+    }
+}
+
+/// Addresses of the replicas described by `SENTINEL replicas`, leaving out the ones down or
+/// disconnected from the primary.
+fn healthy_replicas(replicas: Vec<HashMap<String, String>>) -> Vec<(String, u16)> {
+    replicas
+        .into_iter()
+        .filter(|replica| {
+            let flags = replica.get("flags").map(String::as_str).unwrap_or_default();
+            !flags
+                .split(',')
+                .any(|flag| matches!(flag, "s_down" | "o_down" | "disconnected"))
+                && replica.get("master-link-status").map(String::as_str) == Some("ok")
+        })
+        .filter_map(|replica| {
+            let host = replica.get("ip")?.clone();
+            let port = replica.get("port")?.parse().ok()?;
+            Some((host, port))
+        })
+        .collect()
+}
+
+/// Returns whether the node currently is a primary (`ROLE` is `master`).
+pub(crate) fn is_primary(connection: &mut RedisConnection) -> RedisResult<bool> {
+    let role: Vec<redis::Value> = redis::cmd("ROLE").query(connection)?;
+    match role.first() {
+        Some(value) => Ok(redis::from_redis_value::<String>(value)? == "master"),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use redis::FromRedisValue;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    /// Answers `connections` connections as a sentinel monitoring `mymaster`, with a primary at
+    /// 10.0.0.1:6379 and the replicas of `test_healthy_replicas`, then stops listening.
+    fn fake_sentinel(connections: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut stream = stream.unwrap();
+                let mut parser = redis::Parser::new();
+                while let Ok(value) = parser.parse_value(&mut stream) {
+                    let command = Vec::<String>::from_redis_value(&value).unwrap();
+                    let reply = match (command[1].as_str(), command[2].as_str()) {
+                        ("get-master-addr-by-name", "mymaster" | "noreplicas") => {
+                            "*2\r\n$8\r\n10.0.0.1\r\n$4\r\n6379\r\n".to_owned()
+                        }
+                        ("get-master-addr-by-name", _) => "*-1\r\n".to_owned(),
+                        ("replicas", "noreplicas") => "*0\r\n".to_owned(),
+                        ("replicas", _) => {
+                            let replicas = [
+                                ("10.0.0.2", "slave", "ok"),
+                                ("10.0.0.3", "s_down,slave", "ok"),
+                                ("10.0.0.4", "slave", "ok"),
+                            ];
+                            let mut reply = format!("*{}\r\n", replicas.len());
+                            for (ip, flags, link) in replicas {
+                                let fields = [
+                                    "ip",
+                                    ip,
+                                    "port",
+                                    "6379",
+                                    "flags",
+                                    flags,
+                                    "master-link-status",
+                                    link,
+                                ];
+                                reply.push_str(&format!("*{}\r\n", fields.len()));
+                                for field in fields {
+                                    reply.push_str(&format!("${}\r\n{}\r\n", field.len(), field));
+                                }
+                            }
+                            reply
+                        }
+                        _ => "-ERR unknown command\r\n".to_owned(),
+                    };
+                    stream.write_all(reply.as_bytes()).unwrap();
+                }
+            }
+        });
+        url
+    }
+
+    fn resolver(url: String, master_name: &str, role: NodeRole) -> NodeResolver {
+        let config = SentinelConfig {
+            urls: vec![url],
+            master_name: master_name.to_owned(),
+        };
+        // Bounds waiting on a sentinel which stopped listening after accepting the connection.
+        let connector = Connector::new(None, Some(Duration::from_secs(1))).unwrap();
+        NodeResolver::new(&config, role, connector).unwrap()
+    }
+
+    fn replica(ip: &str, port: &str, flags: &str, link: &str) -> HashMap<String, String> {
+        [
+            ("ip", ip),
+            ("port", port),
+            ("flags", flags),
+            ("master-link-status", link),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect()
+    }
+
+    #[test]
+    fn test_healthy_replicas() {
+        let replicas = vec![
+            replica("10.0.0.2", "6379", "slave", "ok"),
+            replica("10.0.0.3", "6379", "s_down,slave", "ok"),
+            replica("10.0.0.4", "6379", "slave,disconnected", "ok"),
+            replica("10.0.0.5", "6379", "slave", "err"),
+            replica("10.0.0.6", "6380", "slave", "ok"),
+        ];
+        assert_eq!(
+            healthy_replicas(replicas),
+            vec![("10.0.0.2".to_owned(), 6379), ("10.0.0.6".to_owned(), 6380)]
+        );
+    }
+
+    #[test]
+    fn test_resolver_requires_sentinels() {
+        let config = SentinelConfig {
+            urls: Vec::new(),
+            master_name: "mymaster".to_owned(),
+        };
+        let connector = Connector::new(None, None).unwrap();
+        assert!(NodeResolver::new(&config, NodeRole::Primary, connector).is_err());
+    }
+
+    #[test]
+    fn test_resolve_primary() {
+        let tcp = ConnectionAddr::Tcp("127.0.0.1".to_owned(), 6379);
+        let primary = resolver(fake_sentinel(2), "mymaster", NodeRole::Primary);
+        assert_eq!(
+            primary.resolve(&tcp).unwrap(),
+            ConnectionAddr::Tcp("10.0.0.1".to_owned(), 6379)
+        );
+        // The TLS settings of the url are kept.
+        let tls = ConnectionAddr::TcpTls {
+            host: "127.0.0.1".to_owned(),
+            port: 6379,
+            insecure: true,
+        };
+        assert_eq!(
+            primary.resolve(&tls).unwrap(),
+            ConnectionAddr::TcpTls {
+                host: "10.0.0.1".to_owned(),
+                port: 6379,
+                insecure: true
+            }
+        );
+        // The last primary found is used while no sentinel answers.
+        assert_eq!(
+            primary.resolve(&tcp).unwrap(),
+            ConnectionAddr::Tcp("10.0.0.1".to_owned(), 6379)
+        );
+
+        let unknown = resolver(fake_sentinel(1), "othermaster", NodeRole::Primary);
+        assert!(unknown.resolve(&tcp).is_err());
+    }
+
+    #[test]
+    fn test_resolve_replicas() {
+        let tcp = ConnectionAddr::Tcp("127.0.0.1".to_owned(), 6379);
+        let replicas = resolver(fake_sentinel(2), "mymaster", NodeRole::Replica);
+        // The healthy replicas take turns.
+        assert_eq!(
+            replicas.resolve(&tcp).unwrap(),
+            ConnectionAddr::Tcp("10.0.0.2".to_owned(), 6379)
+        );
+        assert_eq!(
+            replicas.resolve(&tcp).unwrap(),
+            ConnectionAddr::Tcp("10.0.0.4".to_owned(), 6379)
+        );
+        // The sentinel is gone by now, and no primary was ever seen.
+        assert!(replicas.resolve(&tcp).is_err());
+
+        // Without any replica, the primary is used.
+        let replicas = resolver(fake_sentinel(2), "noreplicas", NodeRole::Replica);
+        assert_eq!(
+            replicas.resolve(&tcp).unwrap(),
+            ConnectionAddr::Tcp("10.0.0.1".to_owned(), 6379)
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use crate::endpoints;
+use axum::{
+    extract::Extension,
+    routing::{delete, get, post},
+    Router,
+};
+use domain::models::{PayloadFormat, ServerId};
+use domain::services::chat_room::{
+    ChatRoomPublisherService, ChatRoomSubscriberService, SubscriberStats,
+};
+use domain::services::webhook::{RetryPolicy, WebhookDispatcherService};
+use domain::storage::Storage;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Handles to the background services of a server.
+pub struct ServiceHandles {
+    pub server_id: ServerId,
+    pub subscriber_stats: Arc<SubscriberStats>,
+    pub subscriber: std::thread::JoinHandle<()>,
+    pub publisher: std::thread::JoinHandle<()>,
+    pub webhook_dispatcher: std::thread::JoinHandle<()>,
+    pub session_reaper: tokio::task::JoinHandle<()>,
+}
+
+/// Builds a chat server on a storage.
+///
+/// Servers built on storages sharing a dragonfly form a cluster on the chat channel.
+pub struct ChatServerBuilder {
+    storage: Storage,
+    channel_name: String,
+    payload_format: PayloadFormat,
+    ping_interval: Duration,
+    idle_timeout: Duration,
+    broadcast_capacity: usize,
+    publish_queue_capacity: usize,
+    api_keys: Vec<endpoints::ApiKey>,
+    webhook_workers: usize,
+    retry_policy: RetryPolicy,
+    allow_private_webhooks: bool,
+    commands: endpoints::websocket::CommandRegistry,
+}
+
+impl ChatServerBuilder {
+    pub fn new(storage: Storage) -> Self {
+        Self {
+            storage,
+            channel_name: "test".to_owned(),
+            payload_format: PayloadFormat::Json,
+            ping_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(90),
+            broadcast_capacity: 100,
+            publish_queue_capacity: 100,
+            api_keys: Vec::new(),
+            webhook_workers: 4,
+            retry_policy: RetryPolicy::default(),
+            allow_private_webhooks: false,
+            commands: endpoints::websocket::CommandRegistry::default(),
+        }
+    }
+
+    /// Name of the PUBSUB channel shared by the servers of a cluster.
+    pub fn channel_name<S: Into<String>>(mut self, channel_name: S) -> Self {
+        self.channel_name = channel_name.into();
+        self
+    }
+
+    /// Prefix of every dragonfly key and channel, so that deployments can share a database.
+    pub fn key_namespace<S: Into<String>>(mut self, key_namespace: S) -> Self {
+        self.storage = self.storage.with_namespace(key_namespace);
+        self
+    }
+
+    pub fn payload_format(mut self, payload_format: PayloadFormat) -> Self {
+        self.payload_format = payload_format;
+        self
+    }
+
+    /// Time between websocket pings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ping_interval` is zero.
+    pub fn ping_interval(mut self, ping_interval: Duration) -> Self {
+        assert!(!ping_interval.is_zero(), "ping_interval must not be zero");
+        self.ping_interval = ping_interval;
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Number of messages kept for websocket clients lagging behind.
+    pub fn broadcast_capacity(mut self, broadcast_capacity: usize) -> Self {
+        self.broadcast_capacity = broadcast_capacity;
+        self
+    }
+
+    /// Number of messages waiting to be published before senders block.
+    pub fn publish_queue_capacity(mut self, publish_queue_capacity: usize) -> Self {
+        self.publish_queue_capacity = publish_queue_capacity;
+        self
+    }
+
+    /// Adds credentials of integrations.
+    pub fn api_keys<I: IntoIterator<Item = endpoints::ApiKey>>(mut self, api_keys: I) -> Self {
+        self.api_keys.extend(api_keys);
+        self
+    }
+
+    pub fn webhook_workers(mut self, webhook_workers: usize) -> Self {
+        self.webhook_workers = webhook_workers;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Lets webhooks reach `localhost` and private addresses, which are refused by default.
+    pub fn allow_private_webhooks(mut self, allow_private_webhooks: bool) -> Self {
+        self.allow_private_webhooks = allow_private_webhooks;
+        self
+    }
+
+    /// Replaces the built-in slash commands, e.g. by a registry with bots registered.
+    pub fn commands(mut self, commands: endpoints::websocket::CommandRegistry) -> Self {
+        self.commands = commands;
+        self
+    }
+
+    /// Starts the background services and returns the routes of the endpoints.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn build(self) -> (Router, ServiceHandles) {
+        let server_id = ServerId::new();
+        tracing::debug!("server_id: {:?}", &server_id);
+        let (broadcaster, _) = broadcast::channel(self.broadcast_capacity);
+        let (publisher, receiver) = mpsc::sync_channel(self.publish_queue_capacity);
+
+        // start subscriber service async
+        let service = ChatRoomSubscriberService::new(
+            self.storage.clone(),
+            server_id.clone(),
+            self.channel_name.clone(),
+            broadcaster.clone(),
+        );
+        let subscriber_stats = service.stats();
+        let subscriber = std::thread::spawn(move || service.start());
+
+        // start publisher service async
+        let service = ChatRoomPublisherService::new(
+            self.storage.clone(),
+            server_id.clone(),
+            self.channel_name.clone(),
+            self.payload_format,
+            broadcaster.clone(),
+            receiver,
+        );
+        let publisher_thread = std::thread::spawn(move || service.start());
+
+        // start webhook dispatcher service async
+        let service = WebhookDispatcherService::new(
+            self.storage.clone(),
+            server_id.clone(),
+            self.channel_name,
+            self.retry_policy,
+            self.webhook_workers,
+            self.allow_private_webhooks,
+        );
+        let webhook_dispatcher = std::thread::spawn(move || service.start());
+
+        let app_state = Arc::new(
+            endpoints::AppState::new(
+                self.storage,
+                broadcaster,
+                publisher,
+                self.ping_interval,
+                self.idle_timeout,
+                self.api_keys,
+                self.commands,
+            )
+            .allow_private_webhooks(self.allow_private_webhooks),
+        );
+        let session_reaper =
+            tokio::spawn(endpoints::sessions::reap_idle_sessions(app_state.clone()));
+
+        let handles = ServiceHandles {
+            server_id,
+            subscriber_stats,
+            subscriber,
+            publisher: publisher_thread,
+            webhook_dispatcher,
+            session_reaper,
+        };
+        (router(app_state), handles)
+    }
+}
+
+fn router(app_state: Arc<endpoints::AppState>) -> Router {
+    let static_html_routes = Router::new().route("/", get(endpoints::index::handler));
+    let chat_routes = Router::new()
+        .route("/websocket", get(endpoints::websocket::handler))
+        .route(
+            "/rooms",
+            get(endpoints::rooms::list_handler).post(endpoints::rooms::create_handler),
+        )
+        .route(
+            "/rooms/:room",
+            get(endpoints::rooms::show_handler)
+                .patch(endpoints::rooms::update_handler)
+                .delete(endpoints::rooms::delete_handler),
+        )
+        .route(
+            "/rooms/:room/archive",
+            post(endpoints::rooms::archive_handler),
+        )
+        .route(
+            "/rooms/:room/invites",
+            get(endpoints::rooms::invites_handler).post(endpoints::rooms::invite_handler),
+        )
+        .route("/rooms/:room/events", get(endpoints::sse::handler))
+        .route("/rooms/:room/messages", post(endpoints::messages::handler))
+        .route("/rooms/:room/poll", get(endpoints::poll::handler))
+        .route("/rooms/:room/read", post(endpoints::receipts::read_handler))
+        .route("/rooms/:room/search", get(endpoints::search::handler))
+        .route(
+            "/users/:username/unread",
+            get(endpoints::receipts::unread_handler),
+        )
+        .route(
+            "/rooms/:room/sessions",
+            post(endpoints::sessions::create_handler).delete(endpoints::sessions::delete_handler),
+        )
+        .route(
+            "/rooms/:room/webhooks",
+            post(endpoints::webhooks::create_handler).get(endpoints::webhooks::list_handler),
+        )
+        .route(
+            "/rooms/:room/webhooks/dead-letters",
+            get(endpoints::webhooks::dead_letters_handler),
+        )
+        .route(
+            "/rooms/:room/webhooks/:id",
+            delete(endpoints::webhooks::delete_handler),
+        )
+        .layer(Extension(app_state));
+    Router::new().merge(static_html_routes).merge(chat_routes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "ping_interval must not be zero")]
+    fn test_zero_ping_interval() {
+        ChatServerBuilder::new(Storage::memory()).ping_interval(Duration::ZERO);
+    }
+}
@@ -0,0 +1,366 @@
+//! End to end tests booting whole servers in the process, on ephemeral ports.
+//!
+//! Every test gets its own memory storage; servers sharing a storage behave like a cluster
+//! sharing a dragonfly.
+
+use crate::{ChatServerBuilder, ServiceHandles};
+use domain::models::{self, ChatMessage, RoomMessage};
+use domain::storage::Storage;
+use futures::{SinkExt, StreamExt};
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+/// How long a client waits for a frame before the test fails.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct TestServer {
+    addr: SocketAddr,
+    storage: Storage,
+    services: ServiceHandles,
+}
+
+impl TestServer {
+    async fn start(storage: Storage) -> Self {
+        Self::start_with(storage, |builder| builder).await
+    }
+
+    /// Starts a server with changed options.
+    async fn start_with(
+        storage: Storage,
+        configure: impl FnOnce(ChatServerBuilder) -> ChatServerBuilder,
+    ) -> Self {
+        let (app, services) = configure(ChatServerBuilder::new(storage.clone())).build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        // The listener is bound already; waits for the subscriber service to subscribe so that
+        // the server receives what its cluster publishes from now on.
+        tokio::time::timeout(FRAME_TIMEOUT, async {
+            while services.subscriber_stats.subscriptions() == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("the subscriber did not subscribe in time");
+        Self {
+            addr,
+            storage,
+            services,
+        }
+    }
+
+    /// Connects a websocket client and sends its username.
+    async fn join(&self, username: &str) -> TestClient {
+        let url = format!("ws://{}/websocket", self.addr);
+        let (stream, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let mut client = TestClient { stream };
+        client.send(username).await;
+        client
+    }
+
+    /// Opens an event stream on the room and returns it with its session token.
+    async fn events(&self, room_name: &str, username: &str) -> (EventStream, String) {
+        let uri = format!(
+            "http://{}/rooms/{}/events?username={}",
+            self.addr, room_name, username
+        );
+        let response = hyper::Client::new()
+            .get(uri.parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        let mut events = EventStream {
+            body: response.into_body(),
+            buffer: Vec::new(),
+        };
+        let token = events.expect("session").await;
+        events.expect("identity").await;
+        (events, token)
+    }
+
+    /// Posts a chat message with the session token of an event stream.
+    async fn post_message(&self, room_name: &str, token: &str, context: &str) {
+        let request =
+            hyper::Request::post(format!("http://{}/rooms/{}/messages", self.addr, room_name))
+                .header("content-type", "application/json")
+                .header("x-chat-session", token)
+                .body(hyper::Body::from(
+                    serde_json::json!({ "context": context }).to_string(),
+                ))
+                .unwrap();
+        let response = hyper::Client::new().request(request).await.unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::ACCEPTED);
+    }
+
+    /// Publishes payloads on the channel of the cluster as another server would, without
+    /// yielding to the runtime.
+    fn publish(&self, payloads: &[Vec<u8>]) {
+        let mut connection = self.storage.get().unwrap();
+        for payload in payloads {
+            connection.publish("test", payload).unwrap();
+        }
+    }
+}
+
+/// Client side of a Server-Sent Events stream.
+struct EventStream {
+    body: hyper::Body,
+    buffer: Vec<u8>,
+}
+
+impl EventStream {
+    /// Returns the name and the data of the next event, skipping keep-alive comments, or `None`
+    /// once the server ended the stream.
+    async fn next_event(&mut self) -> Option<(String, String)> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|window| window == b"\n\n") {
+                let frame: Vec<u8> = self.buffer.drain(..end + 2).collect();
+                let frame = String::from_utf8(frame).unwrap();
+                let mut event = None;
+                let mut data = String::new();
+                for line in frame.lines() {
+                    let (name, value) = line.split_once(':').unwrap_or((line, ""));
+                    let value = value.strip_prefix(' ').unwrap_or(value);
+                    match name {
+                        "event" => event = Some(value.to_owned()),
+                        "data" => data.push_str(value),
+                        _ => {}
+                    }
+                }
+                match event {
+                    Some(event) => return Some((event, data)),
+                    None => continue,
+                }
+            }
+            let chunk = tokio::time::timeout(FRAME_TIMEOUT, self.body.next())
+                .await
+                .expect("no event received in time");
+            match chunk {
+                Some(Ok(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Some(Err(_)) | None => return None,
+            }
+        }
+    }
+
+    /// Asserts the name of the next event and returns its data.
+    async fn expect(&mut self, event: &str) -> String {
+        let (name, data) = self.next_event().await.expect("the stream ended");
+        assert_eq!(name, event, "{}", data);
+        data
+    }
+
+    /// Returns the chat message carried by the next `message` event.
+    async fn expect_message(&mut self) -> ChatMessage {
+        let data = self.expect("message").await;
+        serde_json::from_str::<RoomMessage>(&data).unwrap().msg
+    }
+}
+
+struct TestClient {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    async fn send(&mut self, text: &str) {
+        self.stream
+            .send(Message::Text(text.to_owned()))
+            .await
+            .unwrap();
+    }
+
+    /// Returns the next text frame, or `None` once the server closed the connection.
+    async fn next_text(&mut self) -> Option<String> {
+        loop {
+            let message = tokio::time::timeout(FRAME_TIMEOUT, self.stream.next())
+                .await
+                .expect("no frame received in time");
+            match message {
+                Some(Ok(Message::Text(text))) => return Some(text),
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+                Some(Ok(message)) => panic!("unexpected frame: {:?}", message),
+            }
+        }
+    }
+
+    /// Asserts the next text frames, in order.
+    async fn expect(&mut self, texts: &[&str]) {
+        for text in texts {
+            assert_eq!(self.next_text().await.as_deref(), Some(*text));
+        }
+    }
+
+    /// Skips the identity token frame sent to the first session of a user.
+    async fn expect_identity_token(&mut self) {
+        let message = tokio::time::timeout(FRAME_TIMEOUT, self.stream.next())
+            .await
+            .expect("no frame received in time");
+        assert!(
+            matches!(message, Some(Ok(Message::Binary(ref token))) if !token.is_empty()),
+            "{:?}",
+            message
+        );
+    }
+
+    async fn close(mut self) {
+        self.stream.close(None).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_join_chat_leave() {
+    let server = TestServer::start(Storage::memory()).await;
+    let mut alice = server.join("alice").await;
+    alice.expect_identity_token().await;
+    alice.expect(&["alice joined."]).await;
+
+    let mut bob = server.join("bob").await;
+    bob.expect_identity_token().await;
+    bob.expect(&["bob joined."]).await;
+    alice.expect(&["bob joined."]).await;
+
+    alice.send("hello").await;
+    for client in [&mut alice, &mut bob] {
+        client.expect(&["alice: hello"]).await;
+    }
+    bob.send("hi").await;
+    for client in [&mut alice, &mut bob] {
+        client.expect(&["bob: hi"]).await;
+    }
+
+    bob.close().await;
+    alice.expect(&["bob left."]).await;
+}
+
+#[tokio::test]
+async fn test_username_taken() {
+    let server = TestServer::start(Storage::memory()).await;
+    let mut alice = server.join("alice").await;
+    alice.expect_identity_token().await;
+    alice.expect(&["alice joined."]).await;
+
+    let mut impostor = server.join("alice").await;
+    impostor.expect(&["Username already taken."]).await;
+    assert_eq!(impostor.next_text().await, None);
+
+    // The rejected client neither joined nor made alice leave.
+    let mut bob = server.join("bob").await;
+    bob.expect_identity_token().await;
+    bob.expect(&["bob joined."]).await;
+    alice.expect(&["bob joined."]).await;
+    alice.send("still here").await;
+    for client in [&mut alice, &mut bob] {
+        client.expect(&["alice: still here"]).await;
+    }
+
+    // The username is free again once alice left.
+    alice.close().await;
+    bob.expect(&["alice left."]).await;
+    let mut alice = server.join("alice").await;
+    alice.expect_identity_token().await;
+    alice.expect(&["alice joined."]).await;
+    bob.expect(&["alice joined."]).await;
+}
+
+#[tokio::test]
+async fn test_cluster() {
+    let storage = Storage::memory();
+    let first = TestServer::start(storage.clone()).await;
+    let second = TestServer::start(storage).await;
+
+    let mut alice = first.join("alice").await;
+    alice.expect_identity_token().await;
+    alice.expect(&["alice joined."]).await;
+    let mut bob = second.join("bob").await;
+    bob.expect_identity_token().await;
+    bob.expect(&["bob joined."]).await;
+    alice.expect(&["bob joined."]).await;
+
+    // Usernames are reserved across the cluster.
+    let mut impostor = second.join("alice").await;
+    impostor.expect(&["Username already taken."]).await;
+
+    alice.send("hello from the first server").await;
+    for client in [&mut alice, &mut bob] {
+        client.expect(&["alice: hello from the first server"]).await;
+    }
+    bob.send("hello from the second server").await;
+    for client in [&mut alice, &mut bob] {
+        client.expect(&["bob: hello from the second server"]).await;
+    }
+
+    alice.close().await;
+    bob.expect(&["alice left."]).await;
+}
+
+#[tokio::test]
+async fn test_events() {
+    let server = TestServer::start(Storage::memory()).await;
+    let mut alice = server.join("alice").await;
+    alice.expect_identity_token().await;
+    alice.expect(&["alice joined."]).await;
+
+    let (mut carol, token) = server.events("test-room", "carol").await;
+    assert!(matches!(
+        carol.expect_message().await,
+        ChatMessage::Join { username, .. } if username == "carol"
+    ));
+    alice.expect(&["carol joined."]).await;
+
+    server.post_message("test-room", &token, "hello").await;
+    alice.expect(&["carol: hello"]).await;
+    assert!(matches!(
+        carol.expect_message().await,
+        ChatMessage::Chat { username, context, .. } if username == "carol" && context == "hello"
+    ));
+    alice.send("hi").await;
+    alice.expect(&["alice: hi"]).await;
+    assert!(matches!(
+        carol.expect_message().await,
+        ChatMessage::Chat { username, context, .. } if username == "alice" && context == "hi"
+    ));
+
+    // Closing the stream leaves the room.
+    drop(carol);
+    alice.expect(&["carol left."]).await;
+}
+
+#[tokio::test]
+async fn test_events_lagged() {
+    let server =
+        TestServer::start_with(Storage::memory(), |builder| builder.broadcast_capacity(2)).await;
+    let (mut carol, _) = server.events("test-room", "carol").await;
+    carol.expect_message().await;
+
+    // The test runs on a single threaded runtime, so the event stream can't receive anything
+    // until the subscriber broadcast every published message, and misses some of them.
+    let other_server = models::ServerId::new();
+    let mut payloads: Vec<_> = (0..5)
+        .map(|i| {
+            let msg = ChatMessage::Chat {
+                username: "dave".to_owned(),
+                room_name: "test-room".to_owned(),
+                context: i.to_string(),
+            };
+            models::encode_payload(
+                models::PayloadFormat::Json.codec(),
+                &models::IdLabeledMessage::new(other_server.clone(), RoomMessage::new(i, msg)),
+            )
+            .unwrap()
+        })
+        .collect();
+    // The subscriber counts this one once it handled the messages before it.
+    payloads.push(b"undecodable".to_vec());
+    server.publish(&payloads);
+    while server.services.subscriber_stats.undecodable_payloads() == 0 {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    assert_eq!(carol.expect("lagged").await, "lagged behind");
+    assert_eq!(carol.next_event().await, None);
+}
@@ -1,2 +1,312 @@
 pub mod index;
+pub mod messages;
+pub mod poll;
+pub mod receipts;
+pub mod rooms;
+pub mod search;
+pub mod sessions;
+pub mod sse;
+pub mod webhooks;
 pub mod websocket;
+
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use domain::storage::{Connection, Storage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Header carrying the token of an HTTP session.
+const SESSION_HEADER: &str = "x-chat-session";
+/// Headers carrying the credentials of a user joining a room, see `JoinHeaders`.
+const PASSWORD_HEADER: &str = "x-chat-password";
+const IDENTITY_HEADER: &str = "x-chat-identity";
+const INVITE_HEADER: &str = "x-chat-invite";
+
+fn session_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(SESSION_HEADER).and_then(|v| v.to_str().ok())
+}
+
+/// Credentials of a user joining a room through a websocket or an event stream, which can't carry
+/// a body: `X-Chat-Password`, `X-Chat-Identity` and `X-Chat-Invite`.
+struct JoinHeaders {
+    password: Option<String>,
+    identity_token: Option<String>,
+    invite_token: Option<String>,
+}
+
+impl JoinHeaders {
+    fn new(headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            password: header(PASSWORD_HEADER),
+            identity_token: header(IDENTITY_HEADER),
+            invite_token: header(INVITE_HEADER),
+        }
+    }
+
+    fn options(&self) -> domain::services::chat_room::JoinOptions {
+        domain::services::chat_room::JoinOptions {
+            password: self.password.as_deref(),
+            identity_token: self.identity_token.as_deref(),
+            invite_token: self.invite_token.as_deref(),
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Credential of an integration allowed to send messages without joining a room.
+///
+/// Parsed from `<name>:<key>`; messages sent with the key are attributed to `name`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ApiKey {
+    name: String,
+    key: String,
+}
+
+impl FromStr for ApiKey {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((name, key)) if !name.is_empty() && !key.is_empty() => Ok(Self {
+                name: name.to_owned(),
+                key: key.to_owned(),
+            }),
+            _ => Err(format!(
+                "api key must be given as <name>:<key>, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+impl ApiKey {
+    /// The same integration with its key replaced, to print it.
+    pub(crate) fn redacted(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            key: crate::config::REDACTED.to_owned(),
+        }
+    }
+}
+
+impl TryFrom<String> for ApiKey {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ApiKey> for String {
+    fn from(api_key: ApiKey) -> Self {
+        format!("{}:{}", api_key.name, api_key.key)
+    }
+}
+
+/// Reason of a rejected request, sent as the response.
+type Rejection = (StatusCode, &'static str);
+
+/// Response to a user who can't join a room.
+fn join_rejection(rejection: domain::services::chat_room::JoinRejection) -> Rejection {
+    use domain::services::{chat_room::JoinRejection, room_access::AccessDenial};
+    match rejection {
+        JoinRejection::UsernameTaken => (StatusCode::CONFLICT, "Username already taken."),
+        JoinRejection::RoomFull => (StatusCode::FORBIDDEN, "Room is full."),
+        JoinRejection::RoomArchived => (StatusCode::GONE, "Room is archived."),
+        JoinRejection::Denied(AccessDenial::NotInvited) => {
+            (StatusCode::FORBIDDEN, "Invitation required.")
+        }
+        JoinRejection::Denied(AccessDenial::WrongPassword) => {
+            (StatusCode::UNAUTHORIZED, "Wrong password.")
+        }
+    }
+}
+
+/// Compares in constant time so that response times don't leak how much of a key matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Room reservation of a client connected through one of the HTTP transports (`sse`, `poll`).
+///
+/// Clients present the token of their session when posting messages.
+pub struct HttpSession {
+    room_name: String,
+    username: String,
+    last_seen: Instant,
+    /// Held by the session itself for long-polling clients, which are reaped once idle.
+    /// The reservation of an `sse` client is held by its event stream instead.
+    chat_room_user: Option<domain::services::chat_room::ChatRoomUser>,
+}
+
+fn storage_request<T>(
+    connection: domain::Result<Box<dyn Connection>>,
+    f: impl FnOnce(&mut dyn Connection) -> domain::Result<T>,
+) -> Result<T, Rejection> {
+    connection
+        .and_then(|mut connection| f(&mut connection))
+        .map_err(|e| {
+            tracing::error!("storage request failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Storage request failed.")
+        })
+}
+
+pub struct AppState {
+    storage: Storage,
+    broadcaster: broadcast::Sender<domain::models::RoomMessage>,
+    publisher: mpsc::SyncSender<domain::models::ChatMessage>,
+    ping_interval: Duration,
+    idle_timeout: Duration,
+    sessions: Mutex<HashMap<String, HttpSession>>,
+    api_keys: Vec<ApiKey>,
+    allow_private_webhooks: bool,
+    commands: websocket::CommandRegistry,
+}
+
+impl AppState {
+    pub fn new(
+        storage: Storage,
+        broadcaster: broadcast::Sender<domain::models::RoomMessage>,
+        publisher: mpsc::SyncSender<domain::models::ChatMessage>,
+        ping_interval: Duration,
+        idle_timeout: Duration,
+        api_keys: Vec<ApiKey>,
+        commands: websocket::CommandRegistry,
+    ) -> Self {
+        Self {
+            storage,
+            broadcaster,
+            publisher,
+            ping_interval,
+            idle_timeout,
+            sessions: Mutex::new(HashMap::new()),
+            api_keys,
+            allow_private_webhooks: false,
+            commands,
+        }
+    }
+
+    /// Accepts webhooks to `localhost` and private addresses.
+    pub fn allow_private_webhooks(mut self, allow_private_webhooks: bool) -> Self {
+        self.allow_private_webhooks = allow_private_webhooks;
+        self
+    }
+
+    /// Returns the name of the integration owning the key.
+    fn integration_name(&self, key: &str) -> Option<&str> {
+        self.api_keys
+            .iter()
+            .filter(|api_key| constant_time_eq(api_key.key.as_bytes(), key.as_bytes()))
+            .map(|api_key| api_key.name.as_str())
+            .last()
+    }
+
+    /// Runs `f` with a storage connection, failing the request if the storage fails.
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&mut dyn Connection) -> domain::Result<T>,
+    ) -> Result<T, Rejection> {
+        storage_request(self.storage.get(), f)
+    }
+
+    /// Runs the reads of `f` with a connection that may lag behind the writes (presence,
+    /// directory), to a replica when the storage has some.
+    fn with_replica_connection<T>(
+        &self,
+        f: impl FnOnce(&mut dyn Connection) -> domain::Result<T>,
+    ) -> Result<T, Rejection> {
+        storage_request(self.storage.get_replica(), f)
+    }
+
+    /// Returns the name of the integration calling with its API key (`Authorization: Bearer <key>`).
+    fn authorize_integration(&self, headers: &HeaderMap) -> Result<&str, Rejection> {
+        match bearer_token(headers).map(|key| self.integration_name(key)) {
+            Some(Some(name)) => Ok(name),
+            Some(None) => Err((StatusCode::UNAUTHORIZED, "Unknown API key.")),
+            None => Err((StatusCode::UNAUTHORIZED, "Missing API key.")),
+        }
+    }
+
+    /// Returns the username of the HTTP session presented in `X-Chat-Session`, which must belong
+    /// to the room, and keeps the session alive.
+    fn session_username(&self, headers: &HeaderMap, room_name: &str) -> Result<String, Rejection> {
+        let token = match session_token(headers) {
+            Some(token) => token,
+            None => return Err((StatusCode::UNAUTHORIZED, "Missing session.")),
+        };
+        match self.sessions.lock().unwrap().get_mut(token) {
+            Some(session) if session.room_name == room_name => {
+                session.last_seen = Instant::now();
+                Ok(session.username.clone())
+            }
+            Some(_) => Err((StatusCode::FORBIDDEN, "Session belongs to another room.")),
+            None => Err((StatusCode::UNAUTHORIZED, "Unknown session.")),
+        }
+    }
+
+    /// Announces the user to the room, unless the user already held another session in it.
+    fn announce_join(&self, chat_room_user: &domain::services::chat_room::ChatRoomUser) {
+        if !chat_room_user.is_first_session() {
+            return;
+        }
+        let msg = domain::models::ChatMessage::Join {
+            username: chat_room_user.username().to_owned(),
+            room_name: chat_room_user.room_name().to_owned(),
+        };
+        tracing::debug!("{:?}", msg);
+        let _ = self.publisher.send(msg);
+    }
+
+    /// Closes the session and announces that the user left once its last session is closed, unless
+    /// the room was deleted with it.
+    fn leave_room(&self, chat_room_user: &mut domain::services::chat_room::ChatRoomUser) {
+        match chat_room_user.leave() {
+            Ok(true) if !chat_room_user.is_room_deleted() => {
+                let msg = domain::models::ChatMessage::Leave {
+                    username: chat_room_user.username().to_owned(),
+                    room_name: chat_room_user.room_name().to_owned(),
+                };
+                tracing::debug!("{:?}", msg);
+                let _ = self.publisher.send(msg);
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("failed to leave {}: {}", chat_room_user.room_name(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_api_key() {
+        let api_key: ApiKey = "ci:s3cr3t:with-colon".parse().unwrap();
+        assert_eq!(api_key.name, "ci");
+        assert_eq!(api_key.key, "s3cr3t:with-colon");
+        assert!("ci".parse::<ApiKey>().is_err());
+        assert!(":key".parse::<ApiKey>().is_err());
+        assert!("ci:".parse::<ApiKey>().is_err());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
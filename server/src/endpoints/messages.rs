@@ -0,0 +1,55 @@
+use super::{bearer_token, AppState};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::{HeaderMap, StatusCode},
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct PostMessage {
+    context: String,
+}
+
+/// Resolves the author of a posted message.
+///
+/// Integrations authenticate with an API key (`Authorization: Bearer <key>`) and post under the
+/// name of their key without joining the room (unless the room is archived). Other clients post
+/// on behalf of the user holding their HTTP session.
+fn author(
+    state: &AppState,
+    headers: &HeaderMap,
+    room_name: &str,
+) -> Result<String, (StatusCode, &'static str)> {
+    if let Some(key) = bearer_token(headers) {
+        let name = match state.integration_name(key) {
+            Some(name) => name.to_owned(),
+            None => return Err((StatusCode::UNAUTHORIZED, "Unknown API key.")),
+        };
+        let metadata = state.with_connection(|connection| {
+            domain::services::room_metadata::room_metadata(connection, room_name)
+        })?;
+        if metadata.archived {
+            return Err((StatusCode::GONE, "Room is archived."));
+        }
+        return Ok(name);
+    }
+    state.session_username(headers, room_name)
+}
+
+/// Sends a chat message to the room.
+pub async fn handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+    Json(body): Json<PostMessage>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let username = author(&state, &headers, &room_name)?;
+    let msg = domain::models::ChatMessage::Chat {
+        username,
+        room_name,
+        context: body.context,
+    };
+    let _ = state.publisher.send(msg);
+    Ok(StatusCode::ACCEPTED)
+}
@@ -0,0 +1,86 @@
+use super::AppState;
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::{HeaderMap, StatusCode},
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Maximum number of messages returned by a single poll.
+const POLL_LIMIT: usize = 100;
+const DEFAULT_POLL_TIMEOUT_SECONDS: u64 = 30;
+const MAX_POLL_TIMEOUT_SECONDS: u64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct PollParams {
+    /// Id of the last message the client received.
+    after: Option<u64>,
+    /// Seconds to wait for a new message when there is none yet.
+    timeout: Option<u64>,
+}
+
+fn messages_after(
+    state: &AppState,
+    room_name: &str,
+    after: u64,
+) -> Result<Vec<domain::models::RoomMessage>, (StatusCode, &'static str)> {
+    state
+        .storage
+        .get()
+        .and_then(|mut connection| {
+            domain::services::room_history::messages_after(
+                &mut connection,
+                room_name,
+                after,
+                POLL_LIMIT,
+            )
+        })
+        .map_err(|e| {
+            tracing::error!("failed to read the history of {}: {}", room_name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read messages.",
+            )
+        })
+}
+
+/// Long-polling transport: returns the messages of the room newer than `after`, waiting up to
+/// `timeout` seconds for one to arrive when there is none yet.
+///
+/// Requires the token of a session in the room (see `sessions::create_handler`), which polling
+/// keeps alive.
+pub async fn handler(
+    Path(room_name): Path<String>,
+    Query(params): Query<PollParams>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<domain::models::RoomMessage>>, (StatusCode, &'static str)> {
+    state.session_username(&headers, &room_name)?;
+    let after = params.after.unwrap_or_default();
+    let timeout = Duration::from_secs(
+        params
+            .timeout
+            .unwrap_or(DEFAULT_POLL_TIMEOUT_SECONDS)
+            .min(MAX_POLL_TIMEOUT_SECONDS),
+    );
+
+    // Subscribe before reading the history so that no message slips in between.
+    let mut broadcast_receiver = state.broadcaster.subscribe();
+    let messages = messages_after(&state, &room_name, after)?;
+    if !messages.is_empty() {
+        return Ok(Json(messages));
+    }
+    let _ = tokio::time::timeout(timeout, async {
+        loop {
+            match broadcast_receiver.recv().await {
+                Ok(msg) if msg.msg.room_name() == room_name && msg.id > after => break,
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+    .await;
+    messages_after(&state, &room_name, after).map(Json)
+}
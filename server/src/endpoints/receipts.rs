@@ -0,0 +1,80 @@
+use super::{bearer_token, session_token, AppState, Rejection};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::{HeaderMap, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct ReadUpTo {
+    /// Id of the last message read, the latest message of the room when omitted.
+    message_id: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadPosition {
+    /// Id of the last message read by the user.
+    message_id: u64,
+}
+
+/// Marks the messages of the room as read for the user of the HTTP session.
+///
+/// A new read position is announced to the room with a `ReadUpTo` event.
+pub async fn read_handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+    Json(body): Json<ReadUpTo>,
+) -> Result<Json<ReadPosition>, Rejection> {
+    let username = state.session_username(&headers, &room_name)?;
+    let (read, message_id) = state.with_connection(|connection| {
+        let read = domain::services::read_receipts::mark_read(
+            connection,
+            &room_name,
+            &username,
+            body.message_id.unwrap_or(u64::MAX),
+        )?;
+        let message_id =
+            domain::services::read_receipts::last_read(connection, &room_name, &username)?;
+        Ok((read, message_id.unwrap_or_default()))
+    })?;
+    if let Some(message_id) = read {
+        let msg = domain::models::ChatMessage::ReadUpTo {
+            username,
+            room_name,
+            message_id,
+        };
+        tracing::debug!("{:?}", msg);
+        let _ = state.publisher.send(msg);
+    }
+    Ok(Json(ReadPosition { message_id }))
+}
+
+/// Returns the number of unread chat messages of the user per room.
+///
+/// Only rooms in which the user marked messages as read are listed. Integrations query any user,
+/// other clients only the user of their HTTP session.
+pub async fn unread_handler(
+    Path(username): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<BTreeMap<String, usize>>, Rejection> {
+    if bearer_token(&headers).is_some() {
+        state.authorize_integration(&headers)?;
+    } else {
+        let token =
+            session_token(&headers).ok_or((StatusCode::UNAUTHORIZED, "Missing session."))?;
+        match state.sessions.lock().unwrap().get(token) {
+            Some(session) if session.username == username => {}
+            Some(_) => return Err((StatusCode::FORBIDDEN, "Session belongs to another user.")),
+            None => return Err((StatusCode::UNAUTHORIZED, "Unknown session.")),
+        }
+    }
+    state
+        .with_connection(|connection| {
+            domain::services::read_receipts::unread_counts(connection, &username)
+        })
+        .map(Json)
+}
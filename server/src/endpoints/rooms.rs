@@ -0,0 +1,271 @@
+use super::{AppState, Rejection};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::{HeaderMap, StatusCode},
+};
+use domain::models::{RoomAccess, RoomMetadata};
+use domain::services::{chat_room, room_access, room_directory, room_metadata};
+use domain::storage::Connection;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::sync::Arc;
+
+#[derive(Debug, Serialize)]
+pub struct RoomInfo {
+    #[serde(flatten)]
+    metadata: RoomMetadata,
+    /// Number of users in the room.
+    members: usize,
+}
+
+/// Distinguishes a field set to `null` (`Some(None)`) from a missing one (`None`).
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Changes to the metadata of a room; missing fields are left as is, `null` fields are cleared.
+#[derive(Debug, Default, Deserialize)]
+pub struct UpdateRoom {
+    #[serde(default, deserialize_with = "nullable")]
+    topic: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    max_members: Option<Option<usize>>,
+    access: Option<RoomAccess>,
+    /// Password of a room with the `password` access; never returned.
+    #[serde(default, deserialize_with = "nullable")]
+    password: Option<Option<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateInvite {
+    username: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InviteCreated {
+    /// Token the invited user joins the room with.
+    invite_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRoom {
+    room_name: String,
+    /// Ephemeral rooms are deleted once their last user leaves.
+    #[serde(default)]
+    ephemeral: bool,
+    #[serde(flatten)]
+    metadata: UpdateRoom,
+}
+
+fn room_info(connection: &mut dyn Connection, room_name: &str) -> domain::Result<RoomInfo> {
+    Ok(RoomInfo {
+        metadata: room_metadata::room_metadata(connection, room_name)?,
+        members: chat_room::room_member_count(connection, room_name)?,
+    })
+}
+
+fn update_room(
+    connection: &mut dyn Connection,
+    room_name: &str,
+    update: &UpdateRoom,
+) -> domain::Result<()> {
+    if let Some(description) = &update.description {
+        room_metadata::set_description(connection, room_name, description.clone())?;
+    }
+    if let Some(max_members) = update.max_members {
+        room_metadata::set_max_members(connection, room_name, max_members)?;
+    }
+    if let Some(topic) = &update.topic {
+        room_metadata::set_topic(connection, room_name, topic.clone())?;
+    }
+    if let Some(access) = update.access {
+        room_metadata::set_access(connection, room_name, access)?;
+    }
+    if let Some(password) = &update.password {
+        room_access::set_password(connection, room_name, password.as_deref())?;
+    }
+    Ok(())
+}
+
+/// Returns the room if it exists.
+fn existing_room(
+    connection: &mut dyn Connection,
+    room_name: &str,
+) -> domain::Result<Option<RoomInfo>> {
+    let room_info = room_info(connection, room_name)?;
+    Ok(room_info.metadata.created_at.map(|_| room_info))
+}
+
+fn room_not_found() -> Rejection {
+    (StatusCode::NOT_FOUND, "Unknown room.")
+}
+
+/// Lists the existing rooms with their number of users.
+pub async fn list_handler(
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<RoomInfo>>, Rejection> {
+    state
+        .with_replica_connection(|connection| {
+            room_directory::room_names(connection)?
+                .iter()
+                .map(|room_name| room_info(connection, room_name))
+                .collect()
+        })
+        .map(Json)
+}
+
+/// Creates a room owned by the integration (integrations only).
+pub async fn create_handler(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+    Json(body): Json<CreateRoom>,
+) -> Result<(StatusCode, Json<RoomInfo>), Rejection> {
+    let integration_name = state.authorize_integration(&headers)?;
+    if body.room_name.is_empty() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "Missing room name."));
+    }
+    let room_info = state.with_connection(|connection| {
+        if !room_directory::create_room(
+            connection,
+            &body.room_name,
+            integration_name,
+            body.ephemeral,
+        )? {
+            return Ok(None);
+        }
+        update_room(connection, &body.room_name, &body.metadata)?;
+        room_info(connection, &body.room_name).map(Some)
+    })?;
+    match room_info {
+        Some(room_info) => Ok((StatusCode::CREATED, Json(room_info))),
+        None => Err((StatusCode::CONFLICT, "Room already exists.")),
+    }
+}
+
+/// Returns the metadata and the number of users of the room.
+pub async fn show_handler(
+    Path(room_name): Path<String>,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<RoomInfo>, Rejection> {
+    state
+        .with_replica_connection(|connection| existing_room(connection, &room_name))?
+        .map(Json)
+        .ok_or_else(room_not_found)
+}
+
+/// Updates the metadata of the room (integrations only).
+///
+/// A topic change is announced to the room on behalf of the integration.
+pub async fn update_handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+    Json(body): Json<UpdateRoom>,
+) -> Result<Json<RoomInfo>, Rejection> {
+    let integration_name = state.authorize_integration(&headers)?.to_owned();
+    let room_info = state
+        .with_connection(|connection| {
+            if existing_room(connection, &room_name)?.is_none() {
+                return Ok(None);
+            }
+            update_room(connection, &room_name, &body)?;
+            room_info(connection, &room_name).map(Some)
+        })?
+        .ok_or_else(room_not_found)?;
+    if let Some(topic) = body.topic {
+        let msg = domain::models::ChatMessage::SetTopic {
+            username: integration_name,
+            room_name,
+            topic,
+        };
+        tracing::debug!("{:?}", msg);
+        let _ = state.publisher.send(msg);
+    }
+    Ok(Json(room_info))
+}
+
+/// Archives the room (integrations only): its history is kept but nobody can join it anymore.
+pub async fn archive_handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<RoomInfo>, Rejection> {
+    state.authorize_integration(&headers)?;
+    state
+        .with_connection(|connection| {
+            if existing_room(connection, &room_name)?.is_none() {
+                return Ok(None);
+            }
+            room_directory::archive_room(connection, &room_name)?;
+            room_info(connection, &room_name).map(Some)
+        })?
+        .map(Json)
+        .ok_or_else(room_not_found)
+}
+
+/// Deletes an empty room with its history and webhooks (integrations only).
+pub async fn delete_handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<StatusCode, Rejection> {
+    state.authorize_integration(&headers)?;
+    state.with_connection(|connection| {
+        if existing_room(connection, &room_name)?.is_none() {
+            return Ok(Err(room_not_found()));
+        }
+        // Users may join meanwhile: only the deletion itself tells whether the room was empty.
+        Ok(
+            match room_directory::delete_empty_room(connection, &room_name)? {
+                true => Ok(StatusCode::NO_CONTENT),
+                false => Err((StatusCode::CONFLICT, "Room is not empty.")),
+            },
+        )
+    })?
+}
+
+/// Lists the users invited to the room (integrations only).
+pub async fn invites_handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<String>>, Rejection> {
+    state.authorize_integration(&headers)?;
+    state
+        .with_connection(|connection| room_access::invited_usernames(connection, &room_name))
+        .map(Json)
+}
+
+/// Lets the user join the room whatever its access with the returned invite token (integrations
+/// only).
+///
+/// The invitation is announced to the room on behalf of the integration.
+pub async fn invite_handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+    Json(body): Json<CreateInvite>,
+) -> Result<(StatusCode, Json<InviteCreated>), Rejection> {
+    let integration_name = state.authorize_integration(&headers)?.to_owned();
+    let invite_token = state
+        .with_connection(|connection| {
+            if existing_room(connection, &room_name)?.is_none() {
+                return Ok(None);
+            }
+            room_access::invite(connection, &room_name, &body.username).map(Some)
+        })?
+        .ok_or_else(room_not_found)?;
+    let msg = domain::models::ChatMessage::Invite {
+        username: integration_name,
+        room_name,
+        invitee: body.username,
+    };
+    tracing::debug!("{:?}", msg);
+    let _ = state.publisher.send(msg);
+    Ok((StatusCode::CREATED, Json(InviteCreated { invite_token })))
+}
@@ -0,0 +1,55 @@
+use super::{bearer_token, AppState, Rejection};
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::{HeaderMap, StatusCode},
+};
+use domain::services::room_search::{self, SearchQuery};
+use serde::Deserialize;
+use std::sync::Arc;
+
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    /// Words which must all appear in the messages.
+    q: String,
+    author: Option<String>,
+    /// Milliseconds since the unix epoch, inclusive.
+    since: Option<i64>,
+    /// Milliseconds since the unix epoch, exclusive.
+    until: Option<i64>,
+    limit: Option<usize>,
+}
+
+/// Searches the chat messages kept in the history of the room, newest first.
+///
+/// Requires the token of a session in the room, or the API key of an integration.
+pub async fn handler(
+    Path(room_name): Path<String>,
+    Query(params): Query<SearchParams>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<domain::models::SearchHit>>, Rejection> {
+    if bearer_token(&headers).is_some() {
+        state.authorize_integration(&headers)?;
+    } else {
+        state.session_username(&headers, &room_name)?;
+    }
+    if params.q.trim().is_empty() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "Missing search terms."));
+    }
+    let query = SearchQuery {
+        text: params.q,
+        author: params.author,
+        since: params.since,
+        until: params.until,
+        limit: params
+            .limit
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .min(MAX_SEARCH_LIMIT),
+    };
+    state
+        .with_connection(|connection| room_search::search(connection, &room_name, &query))
+        .map(Json)
+}
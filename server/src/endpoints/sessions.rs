@@ -0,0 +1,153 @@
+use super::{join_rejection, session_token, AppState, HttpSession, Rejection};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::{HeaderMap, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSession {
+    username: String,
+    /// Password of a password protected room.
+    password: Option<String>,
+    /// Identity token of another session of the user in the room.
+    identity_token: Option<String>,
+    /// Invite token of the user, for a room restricted to invited users.
+    invite_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionCreated {
+    token: String,
+    /// Cursor to start polling from.
+    last_message_id: u64,
+    topic: Option<String>,
+    /// Lets the other devices of the user join the room under the same username.
+    identity_token: String,
+}
+
+/// Joins a room for a long-polling client.
+///
+/// The session stays in the room as long as the client keeps polling with its token (or posting
+/// messages), and leaves it after the idle timeout otherwise.
+pub async fn create_handler(
+    Path(room_name): Path<String>,
+    Extension(state): Extension<Arc<AppState>>,
+    Json(body): Json<CreateSession>,
+) -> Result<(StatusCode, Json<SessionCreated>), Rejection> {
+    let username = body.username;
+    let (chat_room_user, last_message_id, metadata) = state
+        .storage
+        .get()
+        .and_then(|mut connection| {
+            let last_message_id =
+                domain::services::room_history::last_message_id(&mut connection, &room_name)?;
+            let metadata =
+                domain::services::room_metadata::room_metadata(&mut connection, &room_name)?;
+            let chat_room_user = domain::services::chat_room::ChatRoomUser::try_new(
+                state.storage.clone(),
+                &room_name,
+                &username,
+                domain::services::chat_room::JoinOptions {
+                    password: body.password.as_deref(),
+                    identity_token: body.identity_token.as_deref(),
+                    invite_token: body.invite_token.as_deref(),
+                },
+            )?;
+            Ok((chat_room_user, last_message_id, metadata))
+        })
+        .map_err(|e| {
+            tracing::error!("failed to join {}: {}", room_name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to join the room.",
+            )
+        })?;
+    let chat_room_user = chat_room_user.map_err(join_rejection)?;
+    tracing::debug!("username: {} (poll)", username);
+
+    state.announce_join(&chat_room_user);
+    let identity_token = chat_room_user.identity_token().to_owned();
+    let token = uuid::Uuid::new_v4().to_string();
+    state.sessions.lock().unwrap().insert(
+        token.clone(),
+        HttpSession {
+            room_name,
+            username,
+            last_seen: Instant::now(),
+            chat_room_user: Some(chat_room_user),
+        },
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        Json(SessionCreated {
+            token,
+            last_message_id,
+            topic: metadata.topic,
+            identity_token,
+        }),
+    ))
+}
+
+/// Leaves the room of a long-polling session.
+pub async fn delete_handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> StatusCode {
+    let token = match session_token(&headers) {
+        Some(token) => token,
+        None => return StatusCode::UNAUTHORIZED,
+    };
+    let session = {
+        let mut sessions = state.sessions.lock().unwrap();
+        match sessions.get(token) {
+            Some(session) if session.room_name != room_name => return StatusCode::FORBIDDEN,
+            Some(session) if session.chat_room_user.is_none() => return StatusCode::CONFLICT,
+            Some(_) => sessions.remove(token),
+            None => None,
+        }
+    };
+    match session {
+        Some(session) => {
+            leave(&state, session);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::UNAUTHORIZED,
+    }
+}
+
+fn leave(state: &AppState, mut session: HttpSession) {
+    if let Some(chat_room_user) = session.chat_room_user.as_mut() {
+        state.leave_room(chat_room_user);
+    }
+}
+
+/// Removes the long-polling sessions which have not been seen for longer than the idle timeout.
+pub async fn reap_idle_sessions(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(state.ping_interval);
+    loop {
+        interval.tick().await;
+        let idle: Vec<HttpSession> = {
+            let mut sessions = state.sessions.lock().unwrap();
+            let tokens: Vec<String> = sessions
+                .iter()
+                .filter(|(_, session)| {
+                    session.chat_room_user.is_some()
+                        && session.last_seen.elapsed() >= state.idle_timeout
+                })
+                .map(|(token, _)| token.clone())
+                .collect();
+            tokens
+                .iter()
+                .filter_map(|token| sessions.remove(token))
+                .collect()
+        };
+        for session in idle {
+            leave(&state, session);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use super::{join_rejection, AppState, HttpSession, JoinHeaders, Rejection};
+use axum::{
+    extract::{Extension, Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::stream::{self, Stream, StreamExt};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Debug, Deserialize)]
+pub struct JoinParams {
+    username: String,
+}
+
+/// Keeps the room reservation of an event stream alive and leaves the room once the client
+/// disconnects (i.e. when the stream is dropped).
+struct EventStreamGuard {
+    state: Arc<AppState>,
+    token: String,
+    room_name: String,
+    chat_room_user: domain::services::chat_room::ChatRoomUser,
+}
+
+impl Drop for EventStreamGuard {
+    fn drop(&mut self) {
+        self.state.sessions.lock().unwrap().remove(&self.token);
+        self.state.leave_room(&mut self.chat_room_user);
+    }
+}
+
+/// Server-Sent Events fallback of the websocket transport.
+///
+/// The first event (`session`) carries the token to use with `POST /rooms/:room/messages`,
+/// followed by the identity token of the user (`identity`) and the current topic of the room
+/// (`topic`) if any.
+/// Every following event (`message`) carries a JSON encoded `RoomMessage` of the room.
+/// A client too slow to keep up gets a `lagged` event and the stream ends.
+pub async fn handler(
+    Path(room_name): Path<String>,
+    Query(params): Query<JoinParams>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Rejection> {
+    let username = params.username;
+    let credentials = JoinHeaders::new(&headers);
+    let joined = state.storage.get().and_then(|mut connection| {
+        let metadata = domain::services::room_metadata::room_metadata(&mut connection, &room_name)?;
+        let chat_room_user = domain::services::chat_room::ChatRoomUser::try_new(
+            state.storage.clone(),
+            &room_name,
+            &username,
+            credentials.options(),
+        )?;
+        Ok((chat_room_user, metadata))
+    });
+    let (chat_room_user, metadata) = match joined {
+        Ok((chat_room_user, metadata)) => (chat_room_user.map_err(join_rejection)?, metadata),
+        Err(e) => {
+            tracing::error!("failed to join {}: {}", room_name, e);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to join the room.",
+            ));
+        }
+    };
+    tracing::debug!("username: {} (sse)", username);
+
+    let token = uuid::Uuid::new_v4().to_string();
+    state.sessions.lock().unwrap().insert(
+        token.clone(),
+        HttpSession {
+            room_name: room_name.clone(),
+            username,
+            last_seen: Instant::now(),
+            chat_room_user: None,
+        },
+    );
+
+    // Subscribe before sending joined message.
+    let broadcast_receiver = state.broadcaster.subscribe();
+
+    // Send joined message to all subscribers.
+    state.announce_join(&chat_room_user);
+
+    let keep_alive = KeepAlive::new().interval(state.ping_interval);
+    let mut first_events = vec![
+        Ok(Event::default().event("session").data(&token)),
+        Ok(Event::default()
+            .event("identity")
+            .data(chat_room_user.identity_token())),
+    ];
+    if let Some(topic) = metadata.topic {
+        first_events.push(Ok(Event::default().event("topic").data(topic)));
+    }
+    let guard = EventStreamGuard {
+        state,
+        token,
+        room_name,
+        chat_room_user,
+    };
+    let messages = stream::unfold(Some((broadcast_receiver, guard)), |state| async move {
+        let (mut receiver, guard) = state?;
+        loop {
+            match receiver.recv().await {
+                Ok(msg) if msg.msg.room_name() == guard.room_name => {
+                    let event = Event::default().event("message").id(msg.id.to_string());
+                    if let Ok(event) = event.json_data(&msg) {
+                        return Some((Ok(event), Some((receiver, guard))));
+                    }
+                }
+                Ok(_) => continue,
+                // Like a websocket, a stream which missed messages is ended rather than
+                // silently skipping them, so that the client can catch up from the history.
+                Err(RecvError::Lagged(_)) => {
+                    let event = Event::default().event("lagged").data("lagged behind");
+                    return Some((Ok(event), None));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let stream = stream::iter(first_events).chain(messages);
+    Ok(Sse::new(stream).keep_alive(keep_alive))
+}
@@ -0,0 +1,97 @@
+use super::{AppState, Rejection};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::{HeaderMap, StatusCode},
+};
+use domain::models::{DeadLetter, WebhookEvent, WebhookSubscription};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhook {
+    url: String,
+    /// Events to deliver, all of them when omitted.
+    #[serde(default)]
+    events: Vec<WebhookEvent>,
+}
+
+/// A webhook without its secret.
+#[derive(Debug, Serialize)]
+pub struct WebhookSummary {
+    id: String,
+    room_name: String,
+    url: String,
+    events: Vec<WebhookEvent>,
+}
+
+impl From<WebhookSubscription> for WebhookSummary {
+    fn from(subscription: WebhookSubscription) -> Self {
+        Self {
+            id: subscription.id,
+            room_name: subscription.room_name,
+            url: subscription.url,
+            events: subscription.events,
+        }
+    }
+}
+
+/// Subscribes a URL to the events of the room.
+///
+/// The response holds the secret used to sign the deliveries, which isn't returned afterwards.
+pub async fn create_handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+    Json(body): Json<CreateWebhook>,
+) -> Result<(StatusCode, Json<WebhookSubscription>), Rejection> {
+    // Webhooks are managed by integrations only.
+    state.authorize_integration(&headers)?;
+    domain::services::webhook::check_url(&body.url, state.allow_private_webhooks)
+        .map_err(|reason| (StatusCode::UNPROCESSABLE_ENTITY, reason))?;
+    let subscription = state.with_connection(|connection| {
+        domain::services::webhook::add_subscription(connection, &room_name, &body.url, body.events)
+    })?;
+    Ok((StatusCode::CREATED, Json(subscription)))
+}
+
+/// Lists the webhooks of the room, without their secrets.
+pub async fn list_handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<WebhookSummary>>, Rejection> {
+    state.authorize_integration(&headers)?;
+    let subscriptions = state.with_connection(|connection| {
+        domain::services::webhook::subscriptions(connection, &room_name)
+    })?;
+    Ok(Json(subscriptions.into_iter().map(Into::into).collect()))
+}
+
+pub async fn delete_handler(
+    Path((room_name, id)): Path<(String, String)>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<StatusCode, Rejection> {
+    state.authorize_integration(&headers)?;
+    let removed = state.with_connection(|connection| {
+        domain::services::webhook::remove_subscription(connection, &room_name, &id)
+    })?;
+    if removed {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "Unknown webhook."))
+    }
+}
+
+/// Lists the deliveries of the room which failed after all their attempts.
+pub async fn dead_letters_handler(
+    Path(room_name): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<Vec<DeadLetter>>, Rejection> {
+    state.authorize_integration(&headers)?;
+    let dead_letters = state.with_connection(|connection| {
+        domain::services::webhook::dead_letters(connection, &room_name)
+    })?;
+    Ok(Json(dead_letters))
+}
@@ -1,108 +1,280 @@
+mod commands;
+mod wire_format;
+
+pub use commands::{Command, CommandContext, CommandRegistry, Reply};
+pub use wire_format::WireFormat;
+
+use super::{join_rejection, AppState, JoinHeaders};
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
         Extension,
     },
+    http::HeaderMap,
     response::IntoResponse,
 };
-use dragonfly::RedisPool;
+use domain::services::chat_room::RenameRejection;
 use futures::{sink::SinkExt, stream::StreamExt};
-use std::sync::mpsc;
-use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
 
-const DEFAULT_ROOM_NAME: &'static str = "test-room";
-
-pub struct AppState {
-    redis_pool: RedisPool,
-    broadcaster: broadcast::Sender<String>,
-    publisher: mpsc::SyncSender<domain::models::ChatMessage>,
-}
-
-impl AppState {
-    pub fn new(
-        redis_pool: RedisPool,
-        broadcaster: broadcast::Sender<String>,
-        publisher: mpsc::SyncSender<domain::models::ChatMessage>,
-    ) -> Self {
-        Self {
-            redis_pool,
-            broadcaster,
-            publisher,
-        }
-    }
-}
+const DEFAULT_ROOM_NAME: &str = "test-room";
 
 pub async fn handler(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
     Extension(state): Extension<Arc<AppState>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| websocket(socket, state))
+    let format = WireFormat::negotiate(&headers);
+    let ws = match format.subprotocol() {
+        Some(protocol) => ws.protocols([protocol]),
+        None => ws,
+    };
+    let credentials = JoinHeaders::new(&headers);
+    ws.on_upgrade(move |socket| websocket(socket, state, format, credentials))
 }
 
-async fn websocket(stream: WebSocket, state: Arc<AppState>) {
+fn close_message(code: u16, reason: &'static str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
+}
+
+async fn websocket(
+    stream: WebSocket,
+    state: Arc<AppState>,
+    format: WireFormat,
+    credentials: JoinHeaders,
+) {
     // By splitting we can send and receive at the same time.
     let (mut sender, mut receiver) = stream.split();
 
-    // Loop until a text message is found.
-    let mut username = String::new();
-    while let Some(Ok(message)) = receiver.next().await {
-        if let Message::Text(name) = message {
-            username = name;
-            break;
+    // Loop until a username frame is found.
+    // Clients that close or stay silent before choosing a username never join the room.
+    let username = tokio::time::timeout(state.idle_timeout, async {
+        while let Some(Ok(message)) = receiver.next().await {
+            if let Message::Close(frame) = message {
+                tracing::debug!("connection closed before joining: {:?}", frame);
+                return None;
+            }
+            if let Some(name) = format.decode(&message) {
+                return Some(name);
+            }
         }
-    }
-    tracing::debug!("username: {}", username);
-
-    let chat_room_user = match domain::services::chat_room::ChatRoomUser::try_new(
-        state.redis_pool.clone(),
-        DEFAULT_ROOM_NAME,
-        &username,
-    )
-    .unwrap()
-    {
-        Some(chart_room_user) => chart_room_user,
-        None => {
-            // Only send our client that username is taken.
+        None
+    })
+    .await;
+    let username = match username {
+        Ok(Some(username)) => username,
+        Ok(None) => return,
+        Err(_) => {
             let _ = sender
-                .send(Message::Text(String::from("Username already taken.")))
+                .send(close_message(close_code::POLICY, "username not received"))
                 .await;
             return;
         }
     };
+    tracing::debug!("username: {}", username);
+
+    let joined = state
+        .storage
+        .get()
+        .and_then(|mut connection| {
+            domain::services::room_metadata::room_metadata(&mut connection, DEFAULT_ROOM_NAME)
+        })
+        .and_then(|metadata| {
+            domain::services::chat_room::ChatRoomUser::try_new(
+                state.storage.clone(),
+                DEFAULT_ROOM_NAME,
+                &username,
+                credentials.options(),
+            )
+            .map(|joined| (metadata, joined))
+        });
+    let (metadata, chat_room_user) = match joined {
+        Ok((metadata, Ok(chart_room_user))) => (metadata, chart_room_user),
+        Ok((_, Err(rejection))) => {
+            // Only send our client why it can't join.
+            let (_, reason) = join_rejection(rejection);
+            if let Some(notice) = format.encode_notice(reason) {
+                let _ = sender.send(notice).await;
+            }
+            let _ = sender.send(close_message(close_code::POLICY, reason)).await;
+            return;
+        }
+        Err(e) => {
+            tracing::error!("{} failed to join {}: {}", username, DEFAULT_ROOM_NAME, e);
+            let reason = "Failed to join the room, please retry later.";
+            if let Some(notice) = format.encode_notice(reason) {
+                let _ = sender.send(notice).await;
+            }
+            let _ = sender.send(close_message(close_code::ERROR, reason)).await;
+            return;
+        }
+    };
 
     // Subscribe before sending joined message.
     let mut broadcast_receiver = state.broadcaster.subscribe();
 
     // Send joined message to all subscribers.
-    let msg = domain::models::ChatMessage::Join {
-        username: username.clone(),
-        room_name: DEFAULT_ROOM_NAME.to_string(),
-    };
-    tracing::debug!("{:?}", msg);
-    let _ = state.publisher.send(msg);
+    state.announce_join(&chat_room_user);
 
-    // This task will receive broadcast messages and send text message to our client.
+    // Only the first session learns the identity token, the other ones already know it.
+    if chat_room_user.is_first_session() {
+        if let Some(frame) = format.encode_identity(chat_room_user.identity_token()) {
+            let _ = sender.send(frame).await;
+        }
+    }
+
+    if let Some(topic) = metadata.topic {
+        if let Some(notice) = format.encode_notice(&format!("Topic: {}", topic)) {
+            let _ = sender.send(notice).await;
+        }
+    }
+
+    // Any frame from the client (including pongs) counts as a sign of life.
+    let last_seen = Arc::new(Mutex::new(Instant::now()));
+
+    // This task will receive broadcast messages and send them to our client.
+    // It also pings the client periodically and closes the connection once it has been idle
+    // for longer than the idle timeout.
+    let mut ping_interval = tokio::time::interval(state.ping_interval);
+    let idle_timeout = state.idle_timeout;
+    let seen = last_seen.clone();
+    // Replies of commands meant for our client only.
+    let (notice_sender, mut notices) = mpsc::unbounded_channel::<String>();
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = broadcast_receiver.recv().await {
-            // In any websocket error, break loop.
-            if sender.send(Message::Text(msg)).await.is_err() {
-                break;
+        loop {
+            tokio::select! {
+                Some(notice) = notices.recv() => {
+                    let frame = match format.encode_notice(&notice) {
+                        Some(frame) => frame,
+                        None => continue,
+                    };
+                    if sender.send(frame).await.is_err() {
+                        break;
+                    }
+                }
+                received = broadcast_receiver.recv() => {
+                    let msg = match received {
+                        Ok(msg) if msg.msg.room_name() == DEFAULT_ROOM_NAME => msg,
+                        Ok(_) => continue,
+                        // A client which missed messages is disconnected rather than silently
+                        // skipping them, so that it can catch up from the history.
+                        Err(RecvError::Lagged(_)) => {
+                            let _ = sender.send(close_message(close_code::AGAIN, "lagged behind")).await;
+                            break;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    let frame = match format.encode(&msg) {
+                        Some(frame) => frame,
+                        None => continue,
+                    };
+                    // In any websocket error, break loop.
+                    if sender.send(frame).await.is_err() {
+                        break;
+                    }
+                }
+                _ = ping_interval.tick() => {
+                    if seen.lock().unwrap().elapsed() >= idle_timeout {
+                        let _ = sender.send(close_message(close_code::AWAY, "idle timeout")).await;
+                        break;
+                    }
+                    if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                }
             }
         }
     });
 
     // This task will receive messages from client and send them to broadcast subscribers.
-    let name = username.clone();
-    let publisher = state.publisher.clone();
+    // Slash commands are run instead of being sent.
+    // The username may change (`/nick`), so the room reservation is shared with this task.
+    let chat_room_user = Arc::new(Mutex::new(chat_room_user));
+    let user = chat_room_user.clone();
+    let recv_state = state.clone();
     let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(Message::Text(context))) = receiver.next().await {
-            let msg = domain::models::ChatMessage::Chat {
-                username: name.clone(),
-                room_name: DEFAULT_ROOM_NAME.to_string(),
-                context,
+        let state = recv_state;
+        while let Some(Ok(message)) = receiver.next().await {
+            *last_seen.lock().unwrap() = Instant::now();
+            let (name, is_owner) = {
+                let user = user.lock().unwrap();
+                (user.username().to_owned(), user.is_owner())
             };
-            let _ = publisher.send(msg);
+            match message {
+                Message::Text(_) | Message::Binary(_) => match format.decode(&message) {
+                    Some(context) => {
+                        let replies = state
+                            .commands
+                            .dispatch(&state.storage, DEFAULT_ROOM_NAME, &name, is_owner, &context)
+                            .unwrap_or_else(|| {
+                                vec![Reply::Room {
+                                    username: name.clone(),
+                                    context,
+                                }]
+                            });
+                        for reply in replies {
+                            match reply {
+                                Reply::Private(notice) => {
+                                    let _ = notice_sender.send(notice);
+                                }
+                                Reply::Room { username, context } => {
+                                    let msg = domain::models::ChatMessage::Chat {
+                                        username,
+                                        room_name: DEFAULT_ROOM_NAME.to_string(),
+                                        context,
+                                    };
+                                    let _ = state.publisher.send(msg);
+                                }
+                                Reply::Event(msg) => {
+                                    tracing::debug!("{:?}", msg);
+                                    let _ = state.publisher.send(msg);
+                                }
+                                Reply::Rename(new_username) => {
+                                    let renamed = user.lock().unwrap().rename(&new_username);
+                                    match renamed {
+                                        Ok(Ok(())) => {
+                                            let msg = domain::models::ChatMessage::Rename {
+                                                username: name.clone(),
+                                                room_name: DEFAULT_ROOM_NAME.to_string(),
+                                                new_username,
+                                            };
+                                            tracing::debug!("{:?}", msg);
+                                            let _ = state.publisher.send(msg);
+                                        }
+                                        Ok(Err(RenameRejection::UsernameTaken)) => {
+                                            let _ = notice_sender
+                                                .send("Username already taken.".to_owned());
+                                        }
+                                        Ok(Err(RenameRejection::OtherSessions)) => {
+                                            let _ = notice_sender.send(
+                                                "Close your other sessions first.".to_owned(),
+                                            );
+                                        }
+                                        Err(e) => {
+                                            tracing::error!("failed to rename {}: {}", name, e);
+                                            let _ = notice_sender
+                                                .send("Failed to change username.".to_owned());
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    None => tracing::debug!("{} sent an undecodable {:?} frame", name, format),
+                },
+                // Pings are answered by the websocket implementation itself.
+                Message::Ping(_) | Message::Pong(_) => {}
+                // Keep reading so that the close handshake is completed; the stream ends right after.
+                Message::Close(frame) => {
+                    tracing::debug!("{} closed the connection: {:?}", name, frame);
+                }
+            }
         }
     });
 
@@ -113,11 +285,5 @@ async fn websocket(stream: WebSocket, state: Arc<AppState>) {
     };
 
     // Send user left message.
-    let msg = domain::models::ChatMessage::Leave {
-        username: username.clone(),
-        room_name: DEFAULT_ROOM_NAME.to_string(),
-    };
-    tracing::debug!("{:?}", msg);
-    let _ = state.publisher.send(msg);
-    drop(chat_room_user);
+    state.leave_room(&mut chat_room_user.lock().unwrap());
 }
@@ -0,0 +1,454 @@
+use domain::storage::Storage;
+use std::collections::BTreeMap;
+
+/// What a command sends back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// A notice sent to the user who ran the command only.
+    Private(String),
+    /// A chat message published to the room on behalf of `username` (the user or a bot).
+    Room { username: String, context: String },
+    /// Changes the username of the user who ran the command.
+    Rename(String),
+    /// A room event published as is.
+    Event(domain::models::ChatMessage),
+}
+
+/// Everything a command knows about its invocation.
+pub struct CommandContext<'a> {
+    pub storage: &'a Storage,
+    pub room_name: &'a str,
+    pub username: &'a str,
+    /// Whether the user proved to be the owner of the room.
+    pub is_owner: bool,
+    pub registry: &'a CommandRegistry,
+}
+
+/// A slash command (`/<name> <args>`) typed by a websocket client.
+///
+/// Besides the built-in commands, bots are commands replying into the room under their own name.
+pub trait Command: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// One line description shown by `/help`.
+    fn help(&self) -> &'static str;
+    fn execute(&self, context: &CommandContext, args: &str) -> domain::Result<Vec<Reply>>;
+}
+
+/// Commands available to websocket clients, by name.
+pub struct CommandRegistry {
+    commands: BTreeMap<&'static str, Box<dyn Command>>,
+}
+
+impl Default for CommandRegistry {
+    /// Registry of the built-in commands.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(Invite);
+        registry.register(Me);
+        registry.register(Nick);
+        registry.register(Read);
+        registry.register(Topic);
+        registry.register(Who);
+        registry.register(Help);
+        registry
+    }
+}
+
+impl CommandRegistry {
+    pub fn empty() -> Self {
+        Self {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Registers a command, replacing any command with the same name.
+    pub fn register<C: Command + 'static>(&mut self, command: C) {
+        self.commands.insert(command.name(), Box::new(command));
+    }
+
+    pub fn commands(&self) -> impl Iterator<Item = &dyn Command> {
+        self.commands.values().map(AsRef::as_ref)
+    }
+
+    /// Runs the command typed by the user.
+    ///
+    /// Returns `None` when the text is a plain chat message. Text starting with `//` is sent as a
+    /// chat message without its first slash.
+    pub fn dispatch(
+        &self,
+        storage: &Storage,
+        room_name: &str,
+        username: &str,
+        is_owner: bool,
+        text: &str,
+    ) -> Option<Vec<Reply>> {
+        let line = text.strip_prefix('/')?;
+        if line.starts_with('/') {
+            return Some(vec![Reply::Room {
+                username: username.to_owned(),
+                context: line.to_owned(),
+            }]);
+        }
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let command = match self.commands.get(name) {
+            Some(command) => command,
+            None => {
+                return Some(vec![Reply::Private(format!(
+                    "Unknown command /{}. Type /help for the list of commands.",
+                    name
+                ))])
+            }
+        };
+        let context = CommandContext {
+            storage,
+            room_name,
+            username,
+            is_owner,
+            registry: self,
+        };
+        Some(command.execute(&context, args.trim()).unwrap_or_else(|e| {
+            tracing::error!("/{} failed: {}", name, e);
+            vec![Reply::Private(format!("/{} failed.", name))]
+        }))
+    }
+}
+
+/// `/invite <username>` grants the user access to the room (owner only).
+///
+/// The owner gets the invite token to hand over to the user.
+struct Invite;
+
+impl Command for Invite {
+    fn name(&self) -> &'static str {
+        "invite"
+    }
+
+    fn help(&self) -> &'static str {
+        "/invite <username> - let the user join this room"
+    }
+
+    fn execute(&self, context: &CommandContext, args: &str) -> domain::Result<Vec<Reply>> {
+        if args.is_empty() || args.contains(char::is_whitespace) {
+            return Ok(vec![Reply::Private(self.help().to_owned())]);
+        }
+        if !context.is_owner {
+            return Ok(vec![Reply::Private(
+                "Only the owner of the room can invite users.".to_owned(),
+            )]);
+        }
+        let mut connection = context.storage.get()?;
+        let invite_token =
+            domain::services::room_access::invite(&mut connection, context.room_name, args)?;
+        Ok(vec![
+            Reply::Private(format!("Invite token of {}: {}", args, invite_token)),
+            Reply::Event(domain::models::ChatMessage::Invite {
+                username: context.username.to_owned(),
+                room_name: context.room_name.to_owned(),
+                invitee: args.to_owned(),
+            }),
+        ])
+    }
+}
+
+/// `/me <action>` describes what the user does.
+struct Me;
+
+impl Command for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+
+    fn help(&self) -> &'static str {
+        "/me <action> - describe what you are doing"
+    }
+
+    fn execute(&self, context: &CommandContext, args: &str) -> domain::Result<Vec<Reply>> {
+        if args.is_empty() {
+            return Ok(vec![Reply::Private(self.help().to_owned())]);
+        }
+        Ok(vec![Reply::Event(domain::models::ChatMessage::Action {
+            username: context.username.to_owned(),
+            room_name: context.room_name.to_owned(),
+            action: args.to_owned(),
+        })])
+    }
+}
+
+/// `/nick <username>` changes the username without reconnecting.
+struct Nick;
+
+impl Command for Nick {
+    fn name(&self) -> &'static str {
+        "nick"
+    }
+
+    fn help(&self) -> &'static str {
+        "/nick <username> - change your username"
+    }
+
+    fn execute(&self, context: &CommandContext, args: &str) -> domain::Result<Vec<Reply>> {
+        if args.is_empty() || args.contains(char::is_whitespace) {
+            return Ok(vec![Reply::Private(self.help().to_owned())]);
+        }
+        if args == context.username {
+            return Ok(vec![Reply::Private(format!("You are already {}.", args))]);
+        }
+        Ok(vec![Reply::Rename(args.to_owned())])
+    }
+}
+
+/// `/read [id]` marks the messages of the room as read, up to the latest one by default.
+struct Read;
+
+impl Command for Read {
+    fn name(&self) -> &'static str {
+        "read"
+    }
+
+    fn help(&self) -> &'static str {
+        "/read [id] - mark the messages up to the id as read"
+    }
+
+    fn execute(&self, context: &CommandContext, args: &str) -> domain::Result<Vec<Reply>> {
+        let message_id = match args {
+            "" => u64::MAX,
+            args => match args.trim_start_matches('#').parse() {
+                Ok(message_id) => message_id,
+                Err(_) => return Ok(vec![Reply::Private(self.help().to_owned())]),
+            },
+        };
+        let mut connection = context.storage.get()?;
+        let read = domain::services::read_receipts::mark_read(
+            &mut connection,
+            context.room_name,
+            context.username,
+            message_id,
+        )?;
+        Ok(vec![match read {
+            Some(message_id) => Reply::Event(domain::models::ChatMessage::ReadUpTo {
+                username: context.username.to_owned(),
+                room_name: context.room_name.to_owned(),
+                message_id,
+            }),
+            None => Reply::Private("Nothing new to mark as read.".to_owned()),
+        }])
+    }
+}
+
+/// `/topic [topic | --clear]` shows the topic of the room, or sets or clears it for the owner.
+struct Topic;
+
+impl Command for Topic {
+    fn name(&self) -> &'static str {
+        "topic"
+    }
+
+    fn help(&self) -> &'static str {
+        "/topic [topic | --clear] - show the topic of the room, or set or clear it (owner only)"
+    }
+
+    fn execute(&self, context: &CommandContext, args: &str) -> domain::Result<Vec<Reply>> {
+        let mut connection = context.storage.get()?;
+        if args.is_empty() {
+            let metadata =
+                domain::services::room_metadata::room_metadata(&mut connection, context.room_name)?;
+            let notice = match metadata.topic {
+                Some(topic) => format!("Topic: {}", topic),
+                None => "No topic is set.".to_owned(),
+            };
+            return Ok(vec![Reply::Private(notice)]);
+        }
+        if !context.is_owner {
+            return Ok(vec![Reply::Private(
+                "Only the owner of the room can change the topic.".to_owned(),
+            )]);
+        }
+        let topic = match args {
+            "--clear" => None,
+            _ => Some(args.to_owned()),
+        };
+        domain::services::room_metadata::set_topic(
+            &mut connection,
+            context.room_name,
+            topic.clone(),
+        )?;
+        Ok(vec![Reply::Event(domain::models::ChatMessage::SetTopic {
+            username: context.username.to_owned(),
+            room_name: context.room_name.to_owned(),
+            topic,
+        })])
+    }
+}
+
+/// `/who` lists the users in the room.
+struct Who;
+
+impl Command for Who {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+
+    fn help(&self) -> &'static str {
+        "/who - list the users in the room"
+    }
+
+    fn execute(&self, context: &CommandContext, _args: &str) -> domain::Result<Vec<Reply>> {
+        let mut connection = context.storage.get_replica()?;
+        let usernames =
+            domain::services::chat_room::room_members(&mut connection, context.room_name)?;
+        Ok(vec![Reply::Private(format!(
+            "In {}: {}",
+            context.room_name,
+            usernames.join(", ")
+        ))])
+    }
+}
+
+/// `/help` lists the registered commands.
+struct Help;
+
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn help(&self) -> &'static str {
+        "/help - list the commands"
+    }
+
+    fn execute(&self, context: &CommandContext, _args: &str) -> domain::Result<Vec<Reply>> {
+        let lines: Vec<&str> = context.registry.commands().map(Command::help).collect();
+        Ok(vec![Reply::Private(lines.join("\n"))])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Echo;
+
+    impl Command for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn help(&self) -> &'static str {
+            "/echo <text> - have echo-bot repeat the text"
+        }
+
+        fn execute(&self, _context: &CommandContext, args: &str) -> domain::Result<Vec<Reply>> {
+            Ok(vec![Reply::Room {
+                username: "echo-bot".to_owned(),
+                context: args.to_owned(),
+            }])
+        }
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let storage = Storage::memory();
+        let mut registry = CommandRegistry::default();
+        registry.register(Echo);
+        let dispatch = |text| registry.dispatch(&storage, "lobby", "alice", false, text);
+        let owner_dispatch = |text| registry.dispatch(&storage, "lobby", "alice", true, text);
+
+        assert_eq!(dispatch("hello"), None);
+        assert_eq!(
+            dispatch("//etc/hosts"),
+            Some(vec![Reply::Room {
+                username: "alice".to_owned(),
+                context: "/etc/hosts".to_owned(),
+            }])
+        );
+        assert_eq!(
+            dispatch("/me waves"),
+            Some(vec![Reply::Event(domain::models::ChatMessage::Action {
+                username: "alice".to_owned(),
+                room_name: "lobby".to_owned(),
+                action: "waves".to_owned(),
+            })])
+        );
+        let action = domain::models::ChatMessage::Action {
+            username: "alice".to_owned(),
+            room_name: "lobby".to_owned(),
+            action: "waves".to_owned(),
+        };
+        assert_eq!(action.message_context(), "* alice waves");
+        assert_eq!(
+            dispatch("/echo  hi there "),
+            Some(vec![Reply::Room {
+                username: "echo-bot".to_owned(),
+                context: "hi there".to_owned(),
+            }])
+        );
+        assert_eq!(
+            dispatch("/nick bob"),
+            Some(vec![Reply::Rename("bob".to_owned())])
+        );
+        assert_eq!(
+            dispatch("/nick bob marley"),
+            Some(vec![Reply::Private(Nick.help().to_owned())])
+        );
+        assert_eq!(
+            dispatch("/invite bob"),
+            Some(vec![Reply::Private(
+                "Only the owner of the room can invite users.".to_owned()
+            )])
+        );
+        assert!(matches!(
+            owner_dispatch("/invite bob").unwrap().as_slice(),
+            [Reply::Private(notice), Reply::Event(_)] if notice.starts_with("Invite token of bob: ")
+        ));
+        assert_eq!(
+            dispatch("/topic rust"),
+            Some(vec![Reply::Private(
+                "Only the owner of the room can change the topic.".to_owned()
+            )])
+        );
+        assert_eq!(
+            owner_dispatch("/topic rust"),
+            Some(vec![Reply::Event(domain::models::ChatMessage::SetTopic {
+                username: "alice".to_owned(),
+                room_name: "lobby".to_owned(),
+                topic: Some("rust".to_owned()),
+            })])
+        );
+        assert_eq!(
+            dispatch("/topic"),
+            Some(vec![Reply::Private("Topic: rust".to_owned())])
+        );
+        assert_eq!(
+            owner_dispatch("/topic --clear"),
+            Some(vec![Reply::Event(domain::models::ChatMessage::SetTopic {
+                username: "alice".to_owned(),
+                room_name: "lobby".to_owned(),
+                topic: None,
+            })])
+        );
+        assert_eq!(
+            dispatch("/topic"),
+            Some(vec![Reply::Private("No topic is set.".to_owned())])
+        );
+        assert!(matches!(
+            dispatch("/nope").unwrap().as_slice(),
+            [Reply::Private(notice)] if notice.starts_with("Unknown command /nope.")
+        ));
+        assert_eq!(
+            dispatch("/help"),
+            Some(vec![Reply::Private(
+                [
+                    Echo.help(),
+                    Help.help(),
+                    Invite.help(),
+                    Me.help(),
+                    Nick.help(),
+                    Read.help(),
+                    Topic.help(),
+                    Who.help()
+                ]
+                .join("\n")
+            )])
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use axum::{extract::ws::Message, http::HeaderMap};
+use domain::models::RoomMessage;
+use serde::Serialize;
+
+/// Frame carrying the identity token of the user, apart from the messages of the room.
+#[derive(Debug, Serialize)]
+struct IdentityFrame<'a> {
+    identity_token: &'a str,
+}
+
+/// Encoding of the frames exchanged with a websocket client.
+///
+/// The format is negotiated with the `Sec-WebSocket-Protocol` header. Clients that don't ask for
+/// a subprotocol (like `chat.html`) keep the plain text protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Text frames carrying the raw username / chat text, and human readable text from the server.
+    PlainText,
+    /// Text frames carrying JSON encoded strings from the client and JSON encoded `RoomMessage`s
+    /// from the server.
+    Json,
+    /// Binary frames carrying MessagePack encoded strings from the client and MessagePack encoded
+    /// `RoomMessage`s from the server.
+    MessagePack,
+}
+
+impl WireFormat {
+    /// Negotiable formats in decreasing order of preference.
+    const NEGOTIABLE: [Self; 2] = [Self::MessagePack, Self::Json];
+
+    pub fn subprotocol(&self) -> Option<&'static str> {
+        match self {
+            Self::PlainText => None,
+            Self::Json => Some("json"),
+            Self::MessagePack => Some("msgpack"),
+        }
+    }
+
+    /// Picks the preferred format among the subprotocols offered by the client.
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        let offered: Vec<&str> = headers
+            .get_all(axum::http::header::SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        Self::NEGOTIABLE
+            .into_iter()
+            .find(|format| {
+                format
+                    .subprotocol()
+                    .map_or(false, |protocol| offered.contains(&protocol))
+            })
+            .unwrap_or(Self::PlainText)
+    }
+
+    /// Encodes a message for the client.
+    pub fn encode(&self, msg: &RoomMessage) -> Option<Message> {
+        match self {
+            Self::PlainText => Some(Message::Text(msg.msg.message_context())),
+            Self::Json => serde_json::to_string(msg).ok().map(Message::Text),
+            Self::MessagePack => rmp_serde::to_vec_named(msg).ok().map(Message::Binary),
+        }
+    }
+
+    /// Encodes a server notice (e.g. an error) which is not a chat message.
+    pub fn encode_notice(&self, notice: &str) -> Option<Message> {
+        match self {
+            Self::PlainText => Some(Message::Text(notice.to_owned())),
+            Self::Json => serde_json::to_string(notice).ok().map(Message::Text),
+            Self::MessagePack => rmp_serde::to_vec(notice).ok().map(Message::Binary),
+        }
+    }
+
+    /// Encodes the identity token given to the first session of a user.
+    ///
+    /// It never shows up as a chat line: plain text clients get it in a binary frame (all the other
+    /// frames are text), the other formats as an `{"identity_token": ...}` object.
+    pub fn encode_identity(&self, identity_token: &str) -> Option<Message> {
+        let frame = IdentityFrame { identity_token };
+        match self {
+            Self::PlainText => Some(Message::Binary(identity_token.as_bytes().to_vec())),
+            Self::Json => serde_json::to_string(&frame).ok().map(Message::Text),
+            Self::MessagePack => rmp_serde::to_vec_named(&frame).ok().map(Message::Binary),
+        }
+    }
+
+    /// Decodes the text carried by a client data frame.
+    ///
+    /// Returns `None` for frames of the wrong kind or with an undecodable payload.
+    pub fn decode(&self, message: &Message) -> Option<String> {
+        match (self, message) {
+            (Self::PlainText, Message::Text(text)) => Some(text.clone()),
+            (Self::Json, Message::Text(text)) => serde_json::from_str(text).ok(),
+            (Self::MessagePack, Message::Binary(bytes)) => rmp_serde::from_slice(bytes).ok(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::HeaderValue;
+    use domain::models::ChatMessage;
+
+    fn headers(protocols: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(protocols),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            WireFormat::negotiate(&HeaderMap::new()),
+            WireFormat::PlainText
+        );
+        assert_eq!(
+            WireFormat::negotiate(&headers("graphql-ws")),
+            WireFormat::PlainText
+        );
+        assert_eq!(WireFormat::negotiate(&headers("json")), WireFormat::Json);
+        assert_eq!(
+            WireFormat::negotiate(&headers("json, msgpack")),
+            WireFormat::MessagePack
+        );
+    }
+
+    #[test]
+    fn test_message_pack_round_trip() {
+        let format = WireFormat::MessagePack;
+        let frame = Message::Binary(rmp_serde::to_vec("hello").unwrap());
+        assert_eq!(format.decode(&frame), Some("hello".to_owned()));
+        assert_eq!(format.decode(&Message::Text("hello".to_owned())), None);
+
+        let msg = RoomMessage::new(
+            1,
+            ChatMessage::Chat {
+                username: "alice".to_owned(),
+                room_name: "test-room".to_owned(),
+                context: "hello".to_owned(),
+            },
+        );
+        match format.encode(&msg) {
+            Some(Message::Binary(bytes)) => {
+                assert_eq!(rmp_serde::from_slice::<RoomMessage>(&bytes).unwrap(), msg)
+            }
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,12 @@
+//! Chat server library: wires the endpoints and the background services of a server.
+//!
+//! `ChatServerBuilder` returns an axum `Router`, to serve as is or to merge into another app.
+
+mod builder;
+pub mod config;
+#[cfg(test)]
+mod e2e;
+pub mod endpoints;
+pub mod tls;
+
+pub use builder::{ChatServerBuilder, ServiceHandles};
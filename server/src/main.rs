@@ -1,88 +1,265 @@
-mod endpoints;
-
-use axum::{extract::Extension, routing::get, Router};
-use std::{net::SocketAddr, sync::mpsc, sync::Arc};
+use axum_chat_example_server::config::{Backend, Config, ConfigError, TlsConfig};
+use axum_chat_example_server::{endpoints::ApiKey, ChatServerBuilder};
+use std::{path::PathBuf, time::Duration};
 use structopt::StructOpt;
-use tokio::sync::broadcast;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+/// Settings given here (or in their environment variables) override the configuration file.
 #[derive(Debug, StructOpt)]
 #[structopt(about = "axum chat example server running options")]
 struct Opts {
+    #[structopt(
+        long,
+        env = "CHAT_CONFIG",
+        parse(from_os_str),
+        help = "TOML configuration file"
+    )]
+    config: Option<PathBuf>,
+    #[structopt(long, help = "print the resulting configuration and exit")]
+    print_config: bool,
     #[structopt(
         short = "a",
         long,
-        default_value = "0.0.0.0:3000",
-        help = "listen address"
+        env = "CHAT_LISTEN_ADDRESS",
+        help = "listen address [default: 0.0.0.0:3000]"
+    )]
+    listen_address: Option<String>,
+    #[structopt(long, env = "RUST_LOG", help = "log filter [default: info]")]
+    log: Option<String>,
+    #[structopt(
+        long,
+        env = "CHAT_BACKEND",
+        help = "storage of rooms and messages: dragonfly or memory (single server, nothing kept on restart) [default: dragonfly]"
+    )]
+    backend: Option<Backend>,
+    #[structopt(
+        long,
+        env = "REDIS_URL",
+        help = "url of dragonfly [default: redis://127.0.0.1:6379/0]"
+    )]
+    redis_url: Option<String>,
+    #[structopt(
+        long,
+        env = "REDIS_USERNAME",
+        hide_env_values = true,
+        help = "ACL user of dragonfly, overriding the one of the url"
+    )]
+    redis_username: Option<String>,
+    #[structopt(
+        long,
+        env = "REDIS_PASSWORD",
+        hide_env_values = true,
+        help = "password of dragonfly, overriding the one of the url"
+    )]
+    redis_password: Option<String>,
+    #[structopt(
+        long,
+        env = "CHAT_POOL_SIZE",
+        help = "maximum number of dragonfly connections [default: 8]"
     )]
-    listen_address: String,
+    pool_size: Option<u32>,
     #[structopt(
         short = "c",
         long,
-        default_value = "test",
-        help = "dragonfly PUBSUB channel name for chat"
+        env = "CHAT_CHANNEL_NAME",
+        help = "dragonfly PUBSUB channel name for chat [default: test]"
     )]
-    chat_channel_name: String,
+    chat_channel_name: Option<String>,
+    #[structopt(
+        long,
+        env = "CHAT_KEY_NAMESPACE",
+        help = "prefix of every dragonfly key and channel, to share a database between deployments [default: chat]"
+    )]
+    key_namespace: Option<String>,
+    #[structopt(
+        long,
+        env = "CHAT_PAYLOAD_FORMAT",
+        help = "payload format published on the chat channel (json, msgpack or cbor) [default: json]"
+    )]
+    payload_format: Option<domain::models::PayloadFormat>,
+    #[structopt(
+        long,
+        env = "CHAT_PING_INTERVAL",
+        help = "interval in seconds between websocket pings sent to clients [default: 30]"
+    )]
+    ping_interval: Option<u64>,
+    #[structopt(
+        long,
+        env = "CHAT_IDLE_TIMEOUT",
+        help = "seconds without any frame from a client before its connection is closed [default: 90]"
+    )]
+    idle_timeout: Option<u64>,
+    #[structopt(
+        long,
+        env = "CHAT_BROADCAST_CAPACITY",
+        help = "messages kept for websocket clients lagging behind [default: 100]"
+    )]
+    broadcast_capacity: Option<usize>,
+    #[structopt(
+        long,
+        env = "CHAT_PUBLISH_QUEUE_CAPACITY",
+        help = "messages waiting to be published [default: 100]"
+    )]
+    publish_queue_capacity: Option<usize>,
+    #[structopt(
+        long = "api-key",
+        number_of_values = 1,
+        help = "<name>:<key> credential of an integration allowed to post messages (repeatable, added to the configured ones)"
+    )]
+    api_keys: Vec<ApiKey>,
+    #[structopt(
+        long,
+        env = "CHAT_WEBHOOK_WORKERS",
+        help = "number of threads delivering webhook requests [default: 4]"
+    )]
+    webhook_workers: Option<usize>,
+    #[structopt(
+        long,
+        env = "CHAT_TLS_CERT",
+        parse(from_os_str),
+        help = "PEM certificate chain, to serve https:// and wss:// (with --tls-key)"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "CHAT_TLS_KEY",
+        parse(from_os_str),
+        help = "PEM private key of the certificate"
+    )]
+    tls_key: Option<PathBuf>,
+}
+
+fn set<T>(setting: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *setting = value;
+    }
+}
+
+impl Opts {
+    /// Reads the configuration file, if any, and applies the settings given to the command.
+    fn into_config(self) -> Result<Config, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        set(&mut config.listen_address, self.listen_address);
+        set(&mut config.log, self.log);
+        set(&mut config.backend, self.backend);
+        set(&mut config.dragonfly.url, self.redis_url);
+        set(&mut config.dragonfly.pool_size, self.pool_size);
+        if self.redis_username.is_some() {
+            config.dragonfly.username = self.redis_username;
+        }
+        if self.redis_password.is_some() {
+            config.dragonfly.password = self.redis_password;
+        }
+        set(&mut config.chat.channel_name, self.chat_channel_name);
+        set(&mut config.chat.key_namespace, self.key_namespace);
+        set(&mut config.chat.payload_format, self.payload_format);
+        set(&mut config.chat.ping_interval, self.ping_interval);
+        set(&mut config.chat.idle_timeout, self.idle_timeout);
+        set(&mut config.chat.broadcast_capacity, self.broadcast_capacity);
+        set(
+            &mut config.chat.publish_queue_capacity,
+            self.publish_queue_capacity,
+        );
+        config.chat.api_keys.extend(self.api_keys);
+        set(&mut config.webhooks.workers, self.webhook_workers);
+        match (self.tls_cert, self.tls_key, &mut config.tls) {
+            (None, None, _) => {}
+            (cert, key, Some(tls)) => {
+                set(&mut tls.cert, cert);
+                set(&mut tls.key, key);
+            }
+            (Some(cert), Some(key), tls) => *tls = Some(TlsConfig::new(cert, key)),
+            _ => {
+                return Err(ConfigError::Invalid(
+                    "--tls-cert and --tls-key must be given together".to_owned(),
+                ))
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
+    let options: Opts = Opts::from_args();
+    let print_config = options.print_config;
+    let config = match options.into_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
-        ))
+        .with(tracing_subscriber::EnvFilter::new(&config.log))
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let options: Opts = Opts::from_args();
-
-    let redis_url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379/0".to_owned());
-    let redis_pool =
-        dragonfly::new_pool(redis_url, 4).expect("redis connection pool must be initialized.");
-    let server_id = domain::models::ServerId::new();
-    tracing::debug!("server_id: {:?}", &server_id);
-    let channel_name = options.chat_channel_name.clone();
-    let (broadcaster, _) = broadcast::channel(100);
-    let (publisher, receiver) = mpsc::sync_channel(100);
-
-    // start subscriber service async
-    let service = domain::services::chat_room::ChatRoomSubscriberService::new(
-        redis_pool.clone(),
-        server_id.clone(),
-        channel_name.clone(),
-        broadcaster.clone(),
-    );
-    std::thread::spawn(move || service.start());
-
-    // start publisher service async
-    let service = domain::services::chat_room::ChatRoomPublisherService::new(
-        redis_pool.clone(),
-        server_id.clone(),
-        channel_name.clone(),
-        broadcaster.clone(),
-        receiver,
-    );
-    std::thread::spawn(move || service.start());
-
-    let app_state = Arc::new(endpoints::websocket::AppState::new(
-        redis_pool,
-        broadcaster,
-        publisher,
-    ));
-    let static_html_routes = Router::new().route("/", get(endpoints::index::handler));
-    let websocket_routes = Router::new()
-        .route("/websocket", get(endpoints::websocket::handler))
-        .layer(Extension(app_state));
-    let app = Router::new()
-        .merge(static_html_routes)
-        .merge(websocket_routes);
+    let storage = match config.backend {
+        Backend::Dragonfly => {
+            let pool_config = config.dragonfly.pool_config();
+            let redis_pool = dragonfly::build_pool(&pool_config)
+                .expect("redis connection pool must be initialized.");
+            match &config.dragonfly.sentinel {
+                Some(sentinel) if sentinel.read_from_replicas => {
+                    domain::storage::Storage::dragonfly_with_replicas(
+                        redis_pool,
+                        dragonfly::build_replica_pool(&pool_config)
+                            .expect("redis replica connection pool must be initialized."),
+                    )
+                }
+                _ => domain::storage::Storage::dragonfly(redis_pool),
+            }
+        }
+        Backend::Memory => domain::storage::Storage::memory(),
+    };
+    let (app, _services) = ChatServerBuilder::new(storage)
+        .channel_name(config.chat.channel_name.as_str())
+        .key_namespace(config.chat.key_namespace.as_str())
+        .payload_format(config.chat.payload_format)
+        .ping_interval(Duration::from_secs(config.chat.ping_interval))
+        .idle_timeout(Duration::from_secs(config.chat.idle_timeout))
+        .broadcast_capacity(config.chat.broadcast_capacity)
+        .publish_queue_capacity(config.chat.publish_queue_capacity)
+        .api_keys(config.chat.api_keys.iter().cloned())
+        .webhook_workers(config.webhooks.workers)
+        .retry_policy(config.webhooks.retry_policy())
+        .allow_private_webhooks(config.webhooks.allow_private_destinations)
+        .build();
 
-    let addr: SocketAddr = options.listen_address.as_str().parse().unwrap();
-    tracing::debug!("listening on {}", &options.listen_address);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let addr = config.listen_address().unwrap();
+    tracing::debug!("listening on {}", addr);
+    match config.tls {
+        Some(tls) => {
+            let rustls_config =
+                axum_server::tls_rustls::RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                    .await
+                    .expect("TLS certificate must be loaded.");
+            tokio::spawn(axum_chat_example_server::tls::reload_on_change(
+                rustls_config.clone(),
+                tls.cert,
+                tls.key,
+                Duration::from_secs(tls.reload_interval),
+            ));
+            axum_server::bind_rustls(addr, rustls_config)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+        None => axum_server::bind(addr)
+            .serve(app.into_make_service())
+            .await
+            .unwrap(),
+    }
 }
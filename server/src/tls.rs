@@ -0,0 +1,133 @@
+//! TLS termination: serves `https://` and `wss://` with a certificate reloaded when its files change.
+
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Modification times of the certificate and key files, `None` while a file can't be read.
+fn modified_times(cert: &Path, key: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(cert), modified(key))
+}
+
+/// Reloads the certificate whenever one of its files changes, checking every `interval`.
+///
+/// A certificate that fails to load is logged and the previous one is kept until a reload
+/// succeeds, which is attempted again on every check.
+pub async fn reload_on_change(
+    rustls_config: RustlsConfig,
+    cert: PathBuf,
+    key: PathBuf,
+    interval: Duration,
+) {
+    let mut loaded = modified_times(&cert, &key);
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let current = modified_times(&cert, &key);
+        if current == loaded {
+            continue;
+        }
+        // Both files may not be replaced at once, and the second write may keep the modification
+        // time of the first one: a failed attempt is retried until one succeeds.
+        match rustls_config.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => {
+                loaded = current;
+                tracing::info!("reloaded the TLS certificate {}", cert.display());
+            }
+            Err(e) => tracing::error!("failed to reload the TLS certificate: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{routing::get, Router};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls;
+
+    /// Writes a self-signed certificate for `localhost` and returns it in DER.
+    fn write_certificate(cert: &Path, key: &Path) -> Vec<u8> {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        // Every serialization signs anew, so the DER is read back from the written PEM.
+        let pem = certificate.serialize_pem().unwrap();
+        std::fs::write(cert, &pem).unwrap();
+        std::fs::write(key, certificate.serialize_private_key_pem()).unwrap();
+        rustls_pemfile::certs(&mut pem.as_bytes())
+            .unwrap()
+            .remove(0)
+    }
+
+    /// Returns the certificate presented by the server, trusting any of `roots`.
+    async fn peer_certificate(addr: SocketAddr, roots: &[Vec<u8>]) -> Vec<u8> {
+        let mut root_store = rustls::RootCertStore::empty();
+        for root in roots {
+            root_store.add(&rustls::Certificate(root.clone())).unwrap();
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let server_name = rustls::ServerName::try_from("localhost").unwrap();
+        let stream = connector.connect(server_name, stream).await.unwrap();
+        stream.get_ref().1.peer_certificates().unwrap()[0].0.clone()
+    }
+
+    /// Waits for the server to present `expected`.
+    async fn wait_for_certificate(addr: SocketAddr, roots: &[Vec<u8>], expected: &[u8]) {
+        let mut presented = peer_certificate(addr, roots).await;
+        for _ in 0..40 {
+            if presented == expected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            presented = peer_certificate(addr, roots).await;
+        }
+        assert_eq!(presented, expected);
+    }
+
+    #[tokio::test]
+    async fn test_reload_on_change() {
+        let dir = std::env::temp_dir().join(format!("chat-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let first = write_certificate(&cert, &key);
+
+        let rustls_config = RustlsConfig::from_pem_file(&cert, &key).await.unwrap();
+        tokio::spawn(reload_on_change(
+            rustls_config.clone(),
+            cert.clone(),
+            key.clone(),
+            Duration::from_millis(50),
+        ));
+        let handle = axum_server::Handle::new();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        let server = axum_server::bind_rustls("127.0.0.1:0".parse().unwrap(), rustls_config)
+            .handle(handle.clone())
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        let addr = handle.listening().await.unwrap();
+        assert_eq!(peer_certificate(addr, &[first.clone()]).await, first);
+
+        // Some file systems only keep the modification time to the second.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let second = write_certificate(&cert, &key);
+        let roots = [first, second.clone()];
+        wait_for_certificate(addr, &roots, &second).await;
+
+        // A broken pair keeps the current certificate until it is fixed.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        std::fs::write(&key, "broken").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(peer_certificate(addr, &roots).await, second);
+        let third = write_certificate(&cert, &key);
+        let roots = [second.clone(), third.clone()];
+        wait_for_certificate(addr, &roots, &third).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}